@@ -0,0 +1,137 @@
+//! Throughput of token checks under concurrency, comparing Diesel calls made
+//! inline on the async worker threads (how handlers used to work) with the
+//! offloading `LoginManager`.
+//!
+//! Besides operations per second it reports the worst delay of a 1 ms
+//! heartbeat task, which shows how long the runtime workers were stalled.
+//!
+//! Run with `cargo bench --bench login_manager`.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use diesel::r2d2::Pool;
+use rust_test_project::{
+    dbconnection::{DbConnection, DbConnectionManager},
+    loginmanager::{LogMngTrait, LoginManager, DEFAULT_CHECKOUT_TIMEOUT_MS},
+    migrations,
+    models::Session,
+};
+use sha2::{Digest, Sha256};
+
+const OPERATIONS: usize = 2000;
+const CONCURRENCY: usize = 64;
+const POOL_SIZE: u32 = 4;
+
+fn prepare_db() -> String {
+    let path = std::env::temp_dir().join(format!("login_bench_{}.db", std::process::id()));
+    std::fs::remove_file(&path).unwrap_or(());
+    let url = path.to_str().unwrap().to_string();
+    migrations::run(&DbConnection::establish(&url).unwrap()).unwrap();
+    url
+}
+
+/// Old `check_token`: pool checkout and SQLite I/O on the calling thread.
+fn inline_check_token(pool: &Pool<DbConnectionManager>, token: &str) -> bool {
+    let conn = pool.get().unwrap();
+    let token_hash = format!("{:x}", Sha256::digest(token.as_bytes()));
+    match Session::by_token_hash(&conn, token_hash) {
+        Ok(Some(session)) => {
+            let now = chrono::Utc::now().naive_utc();
+            Session::touch(&conn, session.id, now).is_ok()
+        }
+        _ => false,
+    }
+}
+
+async fn heartbeat(stop: Arc<AtomicBool>) -> Duration {
+    let mut worst = Duration::ZERO;
+    while !stop.load(Ordering::Relaxed) {
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        worst = worst.max(start.elapsed().saturating_sub(Duration::from_millis(1)));
+    }
+    worst
+}
+
+async fn measure<F, Fut>(name: &str, op: F)
+where
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: std::future::Future<Output = bool> + Send,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let failures = Arc::new(AtomicUsize::new(0));
+    let beat = tokio::spawn(heartbeat(stop.clone()));
+    let start = Instant::now();
+    let workers: Vec<_> = (0..CONCURRENCY)
+        .map(|_| {
+            let op = op.clone();
+            let failures = failures.clone();
+            tokio::spawn(async move {
+                for _ in 0..OPERATIONS / CONCURRENCY {
+                    if !op().await {
+                        failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    let worst_lag = beat.await.unwrap();
+    println!(
+        "{:<10} {:>8.0} ops/s   worst heartbeat lag {:>6} ms   failed {}",
+        name,
+        (OPERATIONS / CONCURRENCY * CONCURRENCY) as f64 / elapsed.as_secs_f64(),
+        worst_lag.as_millis(),
+        failures.load(Ordering::Relaxed)
+    );
+}
+
+fn main() {
+    let url = prepare_db();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mngr = LoginManager::with_pool_size(url.clone(), POOL_SIZE).unwrap();
+        let token = mngr
+            .create_session("admin".to_string(), None)
+            .await
+            .unwrap()
+            .token;
+
+        let pool = Pool::builder()
+            .max_size(POOL_SIZE)
+            .build(
+                DbConnectionManager::new(url.clone())
+                    .with_busy_timeout(Duration::from_millis(DEFAULT_CHECKOUT_TIMEOUT_MS)),
+            )
+            .unwrap();
+        let inline_token = token.clone();
+        measure("inline", move || {
+            let pool = pool.clone();
+            let token = inline_token.clone();
+            async move { inline_check_token(&pool, &token) }
+        })
+        .await;
+
+        measure("offloaded", move || {
+            let mngr = mngr.clone();
+            let token = token.clone();
+            async move { mngr.check_token(token).await.unwrap_or(false) }
+        })
+        .await;
+    });
+    std::fs::remove_file(url).unwrap_or(());
+}
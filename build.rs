@@ -0,0 +1,5 @@
+fn main() {
+    // Migrations are embedded at compile time; pick up new directories too.
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=migrations_postgres");
+}
@@ -1 +1 @@
-DELETE FROM users
\ No newline at end of file
+SELECT 1;
@@ -1,2 +1,3 @@
-INSERT INTO users (login ,password,token) VALUES ('admin','admin','admin');
-INSERT INTO users (login ,password,token) VALUES ('pacan','bandit','TOAD');
\ No newline at end of file
+-- Used to seed well-known demo accounts. The first administrator is now
+-- created at startup instead, see src/bootstrap.rs.
+SELECT 1;
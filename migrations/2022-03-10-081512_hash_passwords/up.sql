@@ -0,0 +1,3 @@
+ALTER TABLE users RENAME COLUMN password TO password_hash;
+UPDATE users SET password_hash = '$argon2id$v=19$m=4096,t=3,p=1$2JKm3pgLt+Lwdba0QiCWvA$aft9htzjsjfxK5577TAGze1hZxVbKSqpVJF3G6W0mhI' WHERE login = 'admin' AND password_hash = 'admin';
+UPDATE users SET password_hash = '$argon2id$v=19$m=4096,t=3,p=1$atv7wOg17yWOkF1r+YEh6g$ji9+QLKL6B7N0ThGMCvq1t7LcTSbzbOU9rUKU44ZAVs' WHERE login = 'pacan' AND password_hash = 'bandit';
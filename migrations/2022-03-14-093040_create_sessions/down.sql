@@ -0,0 +1,9 @@
+CREATE TABLE users_old (
+  login VARCHAR NOT NULL PRIMARY KEY,
+  password_hash VARCHAR NOT NULL,
+  token VARCHAR NOT NULL UNIQUE
+);
+INSERT INTO users_old (login, password_hash, token) SELECT login, password_hash, lower(hex(randomblob(16))) FROM users;
+DROP TABLE users;
+ALTER TABLE users_old RENAME TO users;
+DROP TABLE sessions;
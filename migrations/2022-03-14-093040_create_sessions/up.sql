@@ -0,0 +1,16 @@
+CREATE TABLE sessions (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  token_hash VARCHAR NOT NULL UNIQUE,
+  login VARCHAR NOT NULL,
+  created DATETIME NOT NULL,
+  expires DATETIME NOT NULL,
+  last_seen DATETIME NOT NULL,
+  user_agent VARCHAR
+);
+CREATE TABLE users_new (
+  login VARCHAR NOT NULL PRIMARY KEY,
+  password_hash VARCHAR NOT NULL
+);
+INSERT INTO users_new (login, password_hash) SELECT login, password_hash FROM users;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
@@ -0,0 +1,5 @@
+-- Drop the demo accounts older installs were seeded with, unless their
+-- password has been changed since.
+DELETE FROM users WHERE login = 'admin' AND password_hash = '$argon2id$v=19$m=4096,t=3,p=1$2JKm3pgLt+Lwdba0QiCWvA$aft9htzjsjfxK5577TAGze1hZxVbKSqpVJF3G6W0mhI';
+DELETE FROM users WHERE login = 'pacan' AND password_hash = '$argon2id$v=19$m=4096,t=3,p=1$atv7wOg17yWOkF1r+YEh6g$ji9+QLKL6B7N0ThGMCvq1t7LcTSbzbOU9rUKU44ZAVs';
+DELETE FROM sessions WHERE login NOT IN (SELECT login FROM users);
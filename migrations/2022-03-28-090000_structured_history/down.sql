@@ -0,0 +1,11 @@
+CREATE TABLE history_old (
+  id VARCHAR PRIMARY KEY NOT NULL,
+  login VARCHAR NOT NULL,
+  request VARCHAR NOT NULL,
+  tms DATETIME NOT NULL
+);
+INSERT INTO history_old (id, login, request, tms)
+SELECT id, login, action || ' ' || target_type || COALESCE(' ' || target_id, ''), tms
+FROM history;
+DROP TABLE history;
+ALTER TABLE history_old RENAME TO history;
@@ -0,0 +1,22 @@
+-- Replace the free-form request text with typed audit columns. The old
+-- text may hold request bodies, passwords included, so older entries keep
+-- neither it nor an outcome or status that was never recorded.
+CREATE TABLE history_new (
+  id VARCHAR PRIMARY KEY NOT NULL,
+  login VARCHAR NOT NULL,
+  tms DATETIME NOT NULL,
+  action VARCHAR NOT NULL,
+  target_type VARCHAR NOT NULL,
+  target_id VARCHAR,
+  outcome VARCHAR NOT NULL,
+  status_code INTEGER,
+  latency_ms BIGINT NOT NULL,
+  client_ip VARCHAR,
+  request_id VARCHAR,
+  payload TEXT
+);
+INSERT INTO history_new (id, login, tms, action, target_type, outcome, latency_ms)
+SELECT id, login, tms, 'legacy', 'unknown', 'unknown', 0
+FROM history;
+DROP TABLE history;
+ALTER TABLE history_new RENAME TO history;
@@ -0,0 +1,20 @@
+CREATE TABLE history_old (
+  id VARCHAR PRIMARY KEY NOT NULL,
+  login VARCHAR NOT NULL,
+  tms DATETIME NOT NULL,
+  action VARCHAR NOT NULL,
+  target_type VARCHAR NOT NULL,
+  target_id VARCHAR,
+  outcome VARCHAR NOT NULL,
+  status_code INTEGER,
+  latency_ms BIGINT NOT NULL,
+  client_ip VARCHAR,
+  request_id VARCHAR,
+  payload TEXT
+);
+INSERT INTO history_old
+SELECT id, login, tms, action, target_type, target_id, outcome, status_code, latency_ms,
+       client_ip, request_id, payload
+FROM history;
+DROP TABLE history;
+ALTER TABLE history_old RENAME TO history;
@@ -0,0 +1,2 @@
+DROP INDEX history_tms_id;
+DROP INDEX history_login_tms_id;
@@ -0,0 +1,4 @@
+-- History is paged by (tms, id): per login, and across all logins for
+-- administrators.
+CREATE INDEX history_login_tms_id ON history (login, tms, id);
+CREATE INDEX history_tms_id ON history (tms, id);
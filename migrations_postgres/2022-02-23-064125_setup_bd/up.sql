@@ -0,0 +1,5 @@
+CREATE TABLE users (
+  login VARCHAR NOT NULL PRIMARY KEY,
+  password VARCHAR NOT NULL,
+  token VARCHAR NOT NULL UNIQUE
+)
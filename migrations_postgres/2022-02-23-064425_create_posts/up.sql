@@ -0,0 +1,3 @@
+-- Used to seed well-known demo accounts. The first administrator is now
+-- created at startup instead, see src/bootstrap.rs.
+SELECT 1;
@@ -0,0 +1,6 @@
+CREATE TABLE history (
+  id VARCHAR PRIMARY KEY NOT NULL,
+  login VARCHAR NOT NULL,
+  request VARCHAR NOT NULL,
+  tms TIMESTAMP NOT NULL
+)
@@ -0,0 +1 @@
+ALTER TABLE users RENAME COLUMN password_hash TO password;
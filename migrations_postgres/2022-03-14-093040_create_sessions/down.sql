@@ -0,0 +1,5 @@
+ALTER TABLE users ADD COLUMN token VARCHAR;
+UPDATE users SET token = md5(random()::text);
+ALTER TABLE users ALTER COLUMN token SET NOT NULL;
+ALTER TABLE users ADD CONSTRAINT users_token_key UNIQUE (token);
+DROP TABLE sessions;
@@ -0,0 +1,10 @@
+CREATE TABLE sessions (
+  id VARCHAR NOT NULL PRIMARY KEY,
+  token_hash VARCHAR NOT NULL UNIQUE,
+  login VARCHAR NOT NULL,
+  created TIMESTAMP NOT NULL,
+  expires TIMESTAMP NOT NULL,
+  last_seen TIMESTAMP NOT NULL,
+  user_agent VARCHAR
+);
+ALTER TABLE users DROP COLUMN token;
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN role VARCHAR NOT NULL DEFAULT 'reader';
+-- Every existing user could change anything before roles existed.
+UPDATE users SET role = 'writer';
+UPDATE users SET role = 'admin' WHERE login = 'admin';
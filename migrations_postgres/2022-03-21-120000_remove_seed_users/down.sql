@@ -0,0 +1,2 @@
+-- The removed demo accounts are not restored.
+SELECT 1;
@@ -0,0 +1,13 @@
+ALTER TABLE history ADD COLUMN request VARCHAR NOT NULL DEFAULT '';
+UPDATE history SET request = action || ' ' || target_type || COALESCE(' ' || target_id, '');
+ALTER TABLE history
+  ALTER COLUMN request DROP DEFAULT,
+  DROP COLUMN action,
+  DROP COLUMN target_type,
+  DROP COLUMN target_id,
+  DROP COLUMN outcome,
+  DROP COLUMN status_code,
+  DROP COLUMN latency_ms,
+  DROP COLUMN client_ip,
+  DROP COLUMN request_id,
+  DROP COLUMN payload;
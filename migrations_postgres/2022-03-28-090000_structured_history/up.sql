@@ -0,0 +1,19 @@
+-- Replace the free-form request text with typed audit columns. The old
+-- text may hold request bodies, passwords included, so older entries keep
+-- neither it nor an outcome or status that was never recorded.
+ALTER TABLE history
+  ADD COLUMN action VARCHAR NOT NULL DEFAULT 'legacy',
+  ADD COLUMN target_type VARCHAR NOT NULL DEFAULT 'unknown',
+  ADD COLUMN target_id VARCHAR,
+  ADD COLUMN outcome VARCHAR NOT NULL DEFAULT 'unknown',
+  ADD COLUMN status_code INTEGER,
+  ADD COLUMN latency_ms BIGINT NOT NULL DEFAULT 0,
+  ADD COLUMN client_ip VARCHAR,
+  ADD COLUMN request_id VARCHAR,
+  ADD COLUMN payload TEXT;
+ALTER TABLE history
+  DROP COLUMN request,
+  ALTER COLUMN action DROP DEFAULT,
+  ALTER COLUMN target_type DROP DEFAULT,
+  ALTER COLUMN outcome DROP DEFAULT,
+  ALTER COLUMN latency_ms DROP DEFAULT;
@@ -0,0 +1,3 @@
+-- Truncated hash of the session token a request presented, so denied
+-- attempts can be correlated without storing the token.
+ALTER TABLE history ADD COLUMN token_fingerprint VARCHAR;
@@ -0,0 +1,483 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+use uuid::Uuid;
+use warp::{http::StatusCode, reply::Response, Filter, Rejection, Reply};
+
+use crate::{
+    config::AuditConfig,
+    loginerror::LoginError,
+    loginmanager::{hash_token, LogMngTrait, SimplifiedUser, UserUpdate},
+    models::History,
+    mydatastruct::{DataQuery, MyData, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE},
+    permissions::{AuthError, Credential, Identity},
+    problem::Problem,
+    tls::PeerAddr,
+};
+
+const REDACTED: &str = "***";
+const MAX_REQUEST_ID_LEN: usize = 64;
+const FINGERPRINT_LEN: usize = 16;
+/// Login recorded for requests whose token matched no user.
+pub const UNKNOWN_LOGIN: &str = "";
+
+/// What a caller did, with the record it touched and, where there is one,
+/// the request body.
+#[derive(Debug, Clone)]
+pub enum AuditEvent {
+    Login { login: String },
+    Logout,
+    ListSessions,
+    RevokeSession { session_id: String },
+    ListUsers,
+    CreateUser(SimplifiedUser),
+    GetUser { login: String },
+    UpdateUser { login: String, user: UserUpdate },
+    DeleteUser { login: String },
+    GetHistory(HistoryQuery),
+    CreateData(MyData),
+    GetData { id: String },
+    ListData(DataQuery),
+    ReplaceData { id: String, data: MyData },
+    PatchData { id: String, patch: Value },
+    DeleteData { id: String },
+}
+
+impl AuditEvent {
+    pub fn action(&self) -> &'static str {
+        match self {
+            AuditEvent::Login { .. } => "login",
+            AuditEvent::Logout => "logout",
+            AuditEvent::ListSessions => "list_sessions",
+            AuditEvent::RevokeSession { .. } => "revoke_session",
+            AuditEvent::ListUsers => "list_users",
+            AuditEvent::CreateUser(_) => "create_user",
+            AuditEvent::GetUser { .. } => "get_user",
+            AuditEvent::UpdateUser { .. } => "update_user",
+            AuditEvent::DeleteUser { .. } => "delete_user",
+            AuditEvent::GetHistory { .. } => "get_history",
+            AuditEvent::CreateData(_) => "create_data",
+            AuditEvent::GetData { .. } => "get_data",
+            AuditEvent::ListData(_) => "list_data",
+            AuditEvent::ReplaceData { .. } => "replace_data",
+            AuditEvent::PatchData { .. } => "patch_data",
+            AuditEvent::DeleteData { .. } => "delete_data",
+        }
+    }
+
+    pub fn target_type(&self) -> &'static str {
+        match self {
+            AuditEvent::Logout | AuditEvent::ListSessions | AuditEvent::RevokeSession { .. } => {
+                "session"
+            }
+            AuditEvent::Login { .. }
+            | AuditEvent::ListUsers
+            | AuditEvent::CreateUser(_)
+            | AuditEvent::GetUser { .. }
+            | AuditEvent::UpdateUser { .. }
+            | AuditEvent::DeleteUser { .. } => "user",
+            AuditEvent::GetHistory { .. } => "history",
+            AuditEvent::CreateData(_)
+            | AuditEvent::GetData { .. }
+            | AuditEvent::ListData(_)
+            | AuditEvent::ReplaceData { .. }
+            | AuditEvent::PatchData { .. }
+            | AuditEvent::DeleteData { .. } => "data",
+        }
+    }
+
+    pub fn target_id(&self) -> Option<String> {
+        match self {
+            AuditEvent::RevokeSession { session_id } => Some(session_id.clone()),
+            AuditEvent::CreateUser(user) => Some(user.login.clone()),
+            AuditEvent::Login { login }
+            | AuditEvent::GetUser { login }
+            | AuditEvent::UpdateUser { login, .. }
+            | AuditEvent::DeleteUser { login } => Some(login.clone()),
+            AuditEvent::GetHistory(query) => query.login.clone(),
+            AuditEvent::CreateData(data) => Some(data.id_getter()),
+            AuditEvent::GetData { id }
+            | AuditEvent::ReplaceData { id, .. }
+            | AuditEvent::PatchData { id, .. }
+            | AuditEvent::DeleteData { id } => Some(id.clone()),
+            AuditEvent::Logout | AuditEvent::ListSessions | AuditEvent::ListUsers => None,
+            AuditEvent::ListData(_) => None,
+        }
+    }
+
+    /// Request body or query, before redaction.
+    pub fn payload(&self) -> Option<Value> {
+        match self {
+            AuditEvent::CreateUser(user) => serde_json::to_value(user).ok(),
+            AuditEvent::UpdateUser { user, .. } => serde_json::to_value(user).ok(),
+            AuditEvent::CreateData(data) | AuditEvent::ReplaceData { data, .. } => {
+                serde_json::to_value(data).ok()
+            }
+            AuditEvent::ListData(query) => serde_json::to_value(query).ok(),
+            AuditEvent::GetHistory(query) => serde_json::to_value(query).ok(),
+            AuditEvent::PatchData { patch, .. } => Some(patch.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    /// The caller was not authenticated, lacked the permission or was
+    /// throttled.
+    Denied,
+    Failure,
+    /// Recorded before outcomes were.
+    Unknown,
+}
+
+impl Outcome {
+    pub fn from_status(status: StatusCode) -> Self {
+        if status == StatusCode::FORBIDDEN
+            || status == StatusCode::UNAUTHORIZED
+            || status == StatusCode::TOO_MANY_REQUESTS
+        {
+            Outcome::Denied
+        } else if status.is_client_error() || status.is_server_error() {
+            Outcome::Failure
+        } else {
+            Outcome::Success
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Denied => "denied",
+            Outcome::Failure => "failure",
+            Outcome::Unknown => "unknown",
+        }
+    }
+}
+
+impl FromStr for Outcome {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "success" => Ok(Outcome::Success),
+            "denied" => Ok(Outcome::Denied),
+            "failure" => Ok(Outcome::Failure),
+            "unknown" => Ok(Outcome::Unknown),
+            _ => Err(format!("Unknown outcome {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Request facts known before the handler runs.
+#[derive(Debug, Clone)]
+pub struct AuditContext {
+    pub request_id: String,
+    pub client_ip: Option<IpAddr>,
+    pub started: Instant,
+}
+
+/// Request id from `x-request-id` when it is short and printable,
+/// a fresh one otherwise.
+pub fn context() -> impl Filter<Extract = (AuditContext,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .and(warp::header::optional::<String>("x-request-id"))
+        .map(
+            |remote: Option<SocketAddr>, peer: Option<PeerAddr>, request_id: Option<String>| {
+                let request_id = request_id
+                    .filter(|x| {
+                        !x.is_empty()
+                            && x.len() <= MAX_REQUEST_ID_LEN
+                            && x.chars().all(|c| c.is_ascii_graphic())
+                    })
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                AuditContext {
+                    request_id,
+                    client_ip: remote.or(peer.map(|x| x.0)).map(|x| x.ip()),
+                    started: Instant::now(),
+                }
+            },
+        )
+}
+
+/// One finished request, ready to be stored.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub login: String,
+    pub event: AuditEvent,
+    pub status: StatusCode,
+    pub latency: Duration,
+    pub client_ip: Option<IpAddr>,
+    pub request_id: String,
+    pub token_fingerprint: Option<String>,
+}
+
+impl AuditEntry {
+    /// Row for the history table; the payload is kept only when `config`
+    /// asks for it, and then with sensitive fields masked.
+    pub fn to_history(&self, config: &AuditConfig) -> History {
+        let payload = if config.capture_payloads {
+            self.event
+                .payload()
+                .map(|x| redact(x, &config.redact_fields).to_string())
+        } else {
+            None
+        };
+        History {
+            id: Uuid::new_v4().to_string(),
+            login: self.login.clone(),
+            tms: chrono::Utc::now().naive_utc(),
+            action: self.event.action().to_string(),
+            target_type: self.event.target_type().to_string(),
+            target_id: self.event.target_id(),
+            outcome: Outcome::from_status(self.status).as_str().to_string(),
+            status_code: Some(i32::from(self.status.as_u16())),
+            latency_ms: self.latency.as_millis() as i64,
+            client_ip: self.client_ip.map(|x| x.to_string()),
+            request_id: Some(self.request_id.clone()),
+            payload,
+            token_fingerprint: self.token_fingerprint.clone(),
+        }
+    }
+}
+
+/// History entry as returned by the API.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub id: String,
+    pub login: String,
+    pub time: NaiveDateTime,
+    pub action: String,
+    pub target_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_id: Option<String>,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_code: Option<u16>,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_fingerprint: Option<String>,
+}
+
+impl From<History> for AuditRecord {
+    fn from(history: History) -> Self {
+        AuditRecord {
+            id: history.id,
+            login: history.login,
+            time: history.tms,
+            action: history.action,
+            target_type: history.target_type,
+            target_id: history.target_id,
+            outcome: history.outcome.parse().unwrap_or(Outcome::Unknown),
+            status_code: history.status_code.map(|x| x as u16),
+            latency_ms: history.latency_ms as u64,
+            client_ip: history.client_ip,
+            request_id: history.request_id,
+            payload: history.payload.and_then(|x| serde_json::from_str(&x).ok()),
+            token_fingerprint: history.token_fingerprint,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Filters and paging of `GET /history`. `from` is inclusive, `to`
+/// exclusive; both are UTC.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct HistoryQuery {
+    pub login: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub action: Option<String>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+    pub order: Option<SortOrder>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HistoryPage {
+    pub items: Vec<AuditRecord>,
+    pub next_cursor: Option<String>,
+}
+
+/// Time and id of the last entry of a page; continuation starts right
+/// after it in the requested order.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HistoryCursor {
+    pub time: NaiveDateTime,
+    pub id: String,
+}
+
+impl HistoryCursor {
+    pub fn encode(&self) -> String {
+        base64::encode_config(
+            serde_json::to_vec(self).unwrap_or_default(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, LoginError> {
+        base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| LoginError::Invalid("Invalid cursor".to_string()))
+    }
+}
+
+impl HistoryQuery {
+    pub fn order(&self) -> SortOrder {
+        self.order.unwrap_or_default()
+    }
+
+    pub fn page_size(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub fn decoded_cursor(&self) -> Result<Option<HistoryCursor>, LoginError> {
+        self.cursor
+            .as_deref()
+            .map(HistoryCursor::decode)
+            .transpose()
+    }
+
+    /// Builds a page out of up to `page_size() + 1` sorted, filtered
+    /// entries, the extra one only telling whether there is a next page.
+    pub fn make_page(&self, mut items: Vec<AuditRecord>) -> HistoryPage {
+        let page_size = self.page_size() as usize;
+        let next_cursor = if items.len() > page_size {
+            items.truncate(page_size);
+            items.last().map(|last| {
+                HistoryCursor {
+                    time: last.time,
+                    id: last.id.clone(),
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        HistoryPage { items, next_cursor }
+    }
+}
+
+/// Masks every object field named in `fields`, ignoring case, at any depth.
+pub fn redact(value: Value, fields: &[String]) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    if fields.iter().any(|x| x.eq_ignore_ascii_case(&key)) {
+                        (key, Value::String(REDACTED.to_string()))
+                    } else {
+                        (key, redact(value, fields))
+                    }
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(|x| redact(x, fields)).collect()),
+        other => other,
+    }
+}
+
+/// Short, stable handle of a session token: the start of the hash the
+/// session is stored under.
+pub fn token_fingerprint(token: &str) -> String {
+    let mut hash = hash_token(token);
+    hash.truncate(FINGERPRINT_LEN);
+    hash
+}
+
+/// Stores how a request with `credential` ended and passes its result on.
+///
+/// Requests whose credential was rejected are kept too, under the login a
+/// client certificate claimed or `UNKNOWN_LOGIN` for a token.
+pub async fn record<R: Reply>(
+    mngr: &impl LogMngTrait,
+    context: AuditContext,
+    credential: &Credential,
+    identity: &Result<Identity, AuthError>,
+    event: AuditEvent,
+    result: Result<R, Rejection>,
+) -> Result<Response, Rejection> {
+    let (login, token) = match (identity, credential) {
+        (Ok(identity), Credential::Token(token)) => (identity.login.clone(), Some(token)),
+        (Ok(identity), Credential::ClientCert(_)) => (identity.login.clone(), None),
+        (Err(_), Credential::Token(token)) => (UNKNOWN_LOGIN.to_string(), Some(token)),
+        (Err(_), Credential::ClientCert(login)) => (login.clone(), None),
+    };
+    record_as(
+        mngr,
+        context,
+        login,
+        token.map(|x| x.as_str()),
+        event,
+        result,
+    )
+    .await
+}
+
+/// Like `record`, for requests that are not authenticated by a credential.
+///
+/// A failed write is logged rather than failing a request that already
+/// took effect.
+pub async fn record_as<R: Reply>(
+    mngr: &impl LogMngTrait,
+    context: AuditContext,
+    login: String,
+    token: Option<&str>,
+    event: AuditEvent,
+    result: Result<R, Rejection>,
+) -> Result<Response, Rejection> {
+    let result = result.map(Reply::into_response);
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(rejection) => rejection
+            .find::<Problem>()
+            .map(Problem::status_code)
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let entry = AuditEntry {
+        login,
+        event,
+        status,
+        latency: context.started.elapsed(),
+        client_ip: context.client_ip,
+        request_id: context.request_id,
+        token_fingerprint: token.map(token_fingerprint),
+    };
+    if let Err(err) = mngr.record_audit(entry).await {
+        warn!("Could not record audit event: {}", err);
+    }
+    result
+}
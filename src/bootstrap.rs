@@ -0,0 +1,102 @@
+use rand::{distributions::Alphanumeric, Rng};
+use tracing::info;
+
+use crate::{
+    loginerror::LoginError,
+    loginmanager::{LogMngTrait, SimplifiedUser},
+    permissions::Role,
+};
+
+pub const DEFAULT_ADMIN_LOGIN: &str = "admin";
+
+/// Accounts the first schema migrations used to create in every database.
+const SEEDED_CREDENTIALS: [(&str, &str); 2] = [("admin", "admin"), ("pacan", "bandit")];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bootstrap {
+    /// The user store already has an administrator.
+    Skipped,
+    Created {
+        login: String,
+    },
+    /// Created with a random password, which is not stored anywhere else.
+    Generated {
+        login: String,
+        password: String,
+    },
+}
+
+/// Creates an administrator when no user has the admin role, be it a new
+/// user store or one whose only administrator was a removed seed account.
+///
+/// Without a `password` a random one is generated and returned so the
+/// caller can show it once.
+pub async fn bootstrap_admin(
+    mngr: &impl LogMngTrait,
+    login: Option<String>,
+    password: Option<String>,
+) -> Result<Bootstrap, LoginError> {
+    if has_admin(mngr).await? {
+        return Ok(Bootstrap::Skipped);
+    }
+    let login = login.unwrap_or_else(|| DEFAULT_ADMIN_LOGIN.to_string());
+    let (password, generated) = match password {
+        Some(password) => (password, false),
+        None => (generate_password(), true),
+    };
+    let created = mngr
+        .insert_new_user(SimplifiedUser {
+            login: login.clone(),
+            password: password.clone(),
+            role: Some(Role::Admin),
+        })
+        .await?;
+    if !created {
+        // Another instance sharing the store may have got there first.
+        if has_admin(mngr).await? {
+            return Ok(Bootstrap::Skipped);
+        }
+        return Err(LoginError::Invalid(format!(
+            "no administrator exists and login {} is taken by another user",
+            login
+        )));
+    }
+    info!("Created bootstrap administrator {}", login);
+    Ok(if generated {
+        Bootstrap::Generated { login, password }
+    } else {
+        Bootstrap::Created { login }
+    })
+}
+
+async fn has_admin(mngr: &impl LogMngTrait) -> Result<bool, LoginError> {
+    Ok(mngr
+        .get_users_list()
+        .await?
+        .iter()
+        .any(|x| x.role == Role::Admin))
+}
+
+/// Logins that still accept one of the publicly known seeded passwords.
+pub async fn default_credentials_in_use(
+    mngr: &impl LogMngTrait,
+) -> Result<Vec<String>, LoginError> {
+    let mut logins = Vec::new();
+    for (login, password) in SEEDED_CREDENTIALS {
+        if mngr
+            .check_user(login.to_string(), password.to_string())
+            .await?
+        {
+            logins.push(login.to_string());
+        }
+    }
+    Ok(logins)
+}
+
+pub(crate) fn generate_password() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
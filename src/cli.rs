@@ -0,0 +1,211 @@
+use std::{fmt, io::Write};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::Subcommand;
+
+use crate::{
+    audit::{HistoryQuery, SortOrder},
+    loginerror::LoginError,
+    loginmanager::{LogMngTrait, LoginManager, SimplifiedUser, UserUpdate},
+    mydatastruct::MAX_PAGE_SIZE,
+    permissions::Role,
+};
+
+/// Maintenance commands working directly on the user store, so an
+/// administrator can be recovered without a valid token.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage users
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Inspect the request history
+    #[clap(subcommand)]
+    History(HistoryCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Create a user; a random password is printed when none is given
+    Add {
+        login: String,
+        #[clap(long, default_value = "reader")]
+        role: Role,
+        #[clap(long)]
+        password: Option<String>,
+    },
+    /// List every user with its role
+    List,
+    /// Set a new password and end the user's sessions; a random password is
+    /// printed when none is given
+    Passwd {
+        login: String,
+        #[clap(long)]
+        password: Option<String>,
+    },
+    /// Delete a user and end its sessions
+    Delete { login: String },
+    /// End every session of a user
+    RevokeTokens { login: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum HistoryCommand {
+    /// Print the requests made by a user, oldest first
+    Show {
+        #[clap(long)]
+        login: String,
+        /// `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`, UTC
+        #[clap(long, value_parser = parse_since)]
+        since: Option<NaiveDateTime>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Store(LoginError),
+    UserExists(String),
+    UnknownUser(String),
+    Output(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Store(err) => write!(f, "{}", err),
+            CommandError::UserExists(login) => write!(f, "user {} already exists", login),
+            CommandError::UnknownUser(login) => write!(f, "no user {}", login),
+            CommandError::Output(err) => write!(f, "can not write output: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<LoginError> for CommandError {
+    fn from(err: LoginError) -> Self {
+        CommandError::Store(err)
+    }
+}
+
+impl From<std::io::Error> for CommandError {
+    fn from(err: std::io::Error) -> Self {
+        CommandError::Output(err.to_string())
+    }
+}
+
+fn parse_since(value: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })
+        .ok_or_else(|| format!("{:?} is neither YYYY-MM-DD nor YYYY-MM-DDTHH:MM:SS", value))
+}
+
+/// Runs `command`, writing its report to `out`.
+pub async fn run(
+    command: Command,
+    mngr: &LoginManager,
+    out: &mut impl Write,
+) -> Result<(), CommandError> {
+    match command {
+        Command::User(UserCommand::Add {
+            login,
+            role,
+            password,
+        }) => {
+            let (password, generated) = password_or_random(password);
+            let created = mngr
+                .insert_new_user(SimplifiedUser {
+                    login: login.clone(),
+                    password: password.clone(),
+                    role: Some(role),
+                })
+                .await?;
+            if !created {
+                return Err(CommandError::UserExists(login));
+            }
+            writeln!(out, "Created {} user {}", role.as_str(), login)?;
+            if generated {
+                writeln!(out, "Password: {}", password)?;
+            }
+        }
+        Command::User(UserCommand::List) => {
+            for user in mngr.get_users_list().await? {
+                writeln!(out, "{}\t{}", user.login, user.role.as_str())?;
+            }
+        }
+        Command::User(UserCommand::Passwd { login, password }) => {
+            let (password, generated) = password_or_random(password);
+            let updated = mngr
+                .update_user(UserUpdate {
+                    login: login.clone(),
+                    password: Some(password.clone()),
+                    role: None,
+                })
+                .await?;
+            if !updated {
+                return Err(CommandError::UnknownUser(login));
+            }
+            writeln!(out, "Password of {} changed", login)?;
+            if generated {
+                writeln!(out, "Password: {}", password)?;
+            }
+            let count = mngr.revoke_all_sessions(login.clone()).await?.unwrap_or(0);
+            writeln!(out, "Revoked {} session(s) of {}", count, login)?;
+        }
+        Command::User(UserCommand::Delete { login }) => {
+            if !mngr.delete_user(login.clone()).await? {
+                return Err(CommandError::UnknownUser(login));
+            }
+            writeln!(out, "Deleted user {}", login)?;
+        }
+        Command::User(UserCommand::RevokeTokens { login }) => {
+            let count = mngr
+                .revoke_all_sessions(login.clone())
+                .await?
+                .ok_or_else(|| CommandError::UnknownUser(login.clone()))?;
+            writeln!(out, "Revoked {} session(s) of {}", count, login)?;
+        }
+        Command::History(HistoryCommand::Show { login, since }) => {
+            let mut query = HistoryQuery {
+                login: Some(login),
+                from: since,
+                limit: Some(MAX_PAGE_SIZE),
+                order: Some(SortOrder::Asc),
+                ..HistoryQuery::default()
+            };
+            loop {
+                let page = mngr.query_history(query.clone()).await?;
+                for elem in page.items {
+                    writeln!(
+                        out,
+                        "{}\t{}\t{} {}\t{}\t{}",
+                        elem.time.format("%Y-%m-%dT%H:%M:%S"),
+                        elem.action,
+                        elem.target_type,
+                        elem.target_id.as_deref().unwrap_or("-"),
+                        elem.outcome,
+                        elem.status_code
+                            .map(|x| x.to_string())
+                            .unwrap_or_else(|| "-".to_string())
+                    )?;
+                }
+                match page.next_cursor {
+                    Some(cursor) => query.cursor = Some(cursor),
+                    None => break,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn password_or_random(password: Option<String>) -> (String, bool) {
+    match password {
+        Some(password) => (password, false),
+        None => (crate::bootstrap::generate_password(), true),
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt;
+
+use mongodb::error::{ErrorKind, WriteFailure};
+use warp::http::StatusCode;
+
+/// Mongo server code for a unique index violation.
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataError {
+    NotFound,
+    Duplicate(String),
+    Validation(String),
+    /// The record changed between reading and writing it.
+    Conflict(String),
+    Unavailable(String),
+    Internal(String),
+}
+
+impl DataError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            DataError::NotFound => StatusCode::NOT_FOUND,
+            DataError::Duplicate(_) => StatusCode::CONFLICT,
+            DataError::Validation(_) => StatusCode::BAD_REQUEST,
+            DataError::Conflict(_) => StatusCode::CONFLICT,
+            DataError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            DataError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataError::NotFound => write!(f, "Not Found!"),
+            DataError::Duplicate(id) => write!(f, "Duplicate id {}", id),
+            DataError::Validation(msg) => write!(f, "{}", msg),
+            DataError::Conflict(id) => write!(f, "Record {} was changed concurrently", id),
+            DataError::Unavailable(_) => write!(f, "Storage unavailable"),
+            DataError::Internal(_) => write!(f, "Internal Error"),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for DataError {
+    fn from(err: mongodb::error::Error) -> Self {
+        match *err.kind {
+            ErrorKind::Write(WriteFailure::WriteError(ref write_err))
+                if write_err.code == DUPLICATE_KEY_CODE =>
+            {
+                DataError::Duplicate(write_err.message.clone())
+            }
+            ErrorKind::ServerSelection { .. }
+            | ErrorKind::Io(_)
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::DnsResolve { .. } => DataError::Unavailable(err.to_string()),
+            _ => DataError::Internal(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{Error, ManageConnection},
+    Connection, ConnectionError, QueryResult, SqliteConnection,
+};
+
+#[cfg(feature = "postgres")]
+use diesel::PgConnection;
+
+/// Diesel backend of the user store, picked from the `DATABASE_URL` scheme.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Sqlite,
+    Postgres,
+}
+
+impl Backend {
+    /// `postgres://` and `postgresql://` URLs select Postgres; anything
+    /// else is taken as a SQLite file name.
+    pub fn from_url(url: &str) -> Self {
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            Backend::Postgres
+        } else {
+            Backend::Sqlite
+        }
+    }
+}
+
+/// Connection to the user store, whichever backend it lives in.
+pub enum DbConnection {
+    Sqlite(SqliteConnection),
+    #[cfg(feature = "postgres")]
+    Pg(PgConnection),
+}
+
+/// Runs `$body` with `$conn` bound to the backend specific connection
+/// behind a `DbConnection`, so Diesel queries are written only once.
+macro_rules! with_connection {
+    ($db:expr, $conn:ident => $body:expr) => {
+        match $db {
+            $crate::dbconnection::DbConnection::Sqlite($conn) => $body,
+            #[cfg(feature = "postgres")]
+            $crate::dbconnection::DbConnection::Pg($conn) => $body,
+        }
+    };
+}
+pub(crate) use with_connection;
+
+impl DbConnection {
+    pub fn establish(url: &str) -> Result<Self, ConnectionError> {
+        match Backend::from_url(url) {
+            Backend::Sqlite => SqliteConnection::establish(url).map(DbConnection::Sqlite),
+            #[cfg(feature = "postgres")]
+            Backend::Postgres => PgConnection::establish(url).map(DbConnection::Pg),
+            #[cfg(not(feature = "postgres"))]
+            Backend::Postgres => Err(ConnectionError::BadConnection(
+                "built without the postgres feature".to_string(),
+            )),
+        }
+    }
+
+    /// Runs `f` in a transaction that is rolled back when it fails.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        with_connection!(self, conn => conn.transaction(f))
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self {
+            DbConnection::Sqlite(_) => Backend::Sqlite,
+            #[cfg(feature = "postgres")]
+            DbConnection::Pg(_) => Backend::Postgres,
+        }
+    }
+}
+
+impl SimpleConnection for DbConnection {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        with_connection!(self, conn => conn.batch_execute(query))
+    }
+}
+
+/// r2d2 manager handing out `DbConnection`s for one database URL.
+#[derive(Debug)]
+pub struct DbConnectionManager {
+    url: String,
+    busy_timeout: Duration,
+}
+
+impl DbConnectionManager {
+    pub fn new(url: impl Into<String>) -> Self {
+        DbConnectionManager {
+            url: url.into(),
+            busy_timeout: Duration::ZERO,
+        }
+    }
+
+    /// Makes concurrent SQLite writers wait for the lock instead of failing
+    /// straight away with "database is locked". Postgres ignores it.
+    pub fn with_busy_timeout(mut self, busy_timeout: Duration) -> Self {
+        self.busy_timeout = busy_timeout;
+        self
+    }
+}
+
+impl ManageConnection for DbConnectionManager {
+    type Connection = DbConnection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<DbConnection, Error> {
+        let conn = DbConnection::establish(&self.url).map_err(Error::ConnectionError)?;
+        match &conn {
+            DbConnection::Sqlite(sqlite) => sqlite
+                .batch_execute(&format!(
+                    "PRAGMA busy_timeout = {};",
+                    self.busy_timeout.as_millis()
+                ))
+                .map_err(Error::QueryError)?,
+            #[cfg(feature = "postgres")]
+            DbConnection::Pg(_) => {}
+        }
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut DbConnection) -> Result<(), Error> {
+        conn.batch_execute("SELECT 1").map_err(Error::QueryError)
+    }
+
+    fn has_broken(&self, _conn: &mut DbConnection) -> bool {
+        false
+    }
+}
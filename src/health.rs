@@ -0,0 +1,87 @@
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::warn;
+use warp::{http::StatusCode, reply, Rejection};
+
+use crate::{loginmanager::LogMngTrait, mongodbprovider::MongoDBProviderTrait};
+
+/// Longest a single dependency check may take before it counts as down.
+pub const CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DependencyStatus {
+    pub status: Status,
+    pub latency_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of `/readyz`: overall status plus one entry per dependency.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Readiness {
+    pub status: Status,
+    pub checks: BTreeMap<String, DependencyStatus>,
+}
+
+/// Runs `check`, giving up after `CHECK_TIMEOUT`.
+pub async fn check<E: Display>(check: impl Future<Output = Result<(), E>>) -> DependencyStatus {
+    let started = Instant::now();
+    let error = match time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+    DependencyStatus {
+        status: if error.is_none() {
+            Status::Up
+        } else {
+            Status::Down
+        },
+        latency_ms: started.elapsed().as_millis() as u64,
+        error,
+    }
+}
+
+pub async fn readiness(db: &impl MongoDBProviderTrait, mngr: &impl LogMngTrait) -> Readiness {
+    let (mongo, user_store) = tokio::join!(check(db.ping()), check(mngr.ping()));
+    let checks = BTreeMap::from([
+        ("mongo".to_string(), mongo),
+        ("user_store".to_string(), user_store),
+    ]);
+    let status = if checks.values().all(|x| x.status == Status::Up) {
+        Status::Up
+    } else {
+        Status::Down
+    };
+    Readiness { status, checks }
+}
+
+/// The process is up and serving requests.
+pub async fn liveness_handler() -> Result<impl warp::Reply, Rejection> {
+    Ok(reply::json(&serde_json::json!({"status": Status::Up})))
+}
+
+/// Ready when both stores answer; 503 otherwise.
+pub async fn readiness_handler(
+    db: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+) -> Result<impl warp::Reply, Rejection> {
+    let readiness = readiness(&db, &mngr).await;
+    let status = match readiness.status {
+        Status::Up => StatusCode::OK,
+        Status::Down => {
+            warn!("Not ready: {:?}", readiness.checks);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    };
+    Ok(reply::with_status(reply::json(&readiness), status))
+}
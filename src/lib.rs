@@ -1,14 +1,41 @@
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+pub mod audit;
+pub mod bootstrap;
+pub mod cli;
+pub mod config;
+pub mod dataerror;
+pub mod dbconnection;
+pub mod health;
+pub mod loginerror;
 pub mod loginmanager;
+pub mod metrics;
+pub mod migrations;
+// Diesel 1.4 derives and `table!` expand to impls that newer rustc flags.
+#[allow(non_local_definitions)]
 pub mod models;
 pub mod mongodbprovider;
 #[cfg(test)]
 mod my_tests;
 pub mod mydatastruct;
+pub mod passwordhash;
+pub mod permissions;
+pub mod problem;
+pub mod ratelimit;
+pub mod retention;
 pub mod routes;
+#[allow(non_local_definitions)]
 pub mod schema;
 #[cfg(test)]
+mod testconfig;
+#[cfg(test)]
 pub mod testlogin;
 #[cfg(test)]
 pub mod testsmongo;
+#[cfg(test)]
+mod testspostgres;
+#[cfg(test)]
+mod testtls;
+pub mod tls;
@@ -0,0 +1,60 @@
+use std::fmt;
+
+use diesel::result::{DatabaseErrorKind, Error};
+use warp::http::StatusCode;
+
+/// Failure of the user store itself, as opposed to a negative answer
+/// such as an unknown login or a wrong password.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginError {
+    /// No connection could be checked out in time, or the database file
+    /// is locked or can not be opened.
+    Unavailable(String),
+    /// The request asked for something malformed, such as a bad cursor.
+    Invalid(String),
+    Internal(String),
+}
+
+impl LoginError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            LoginError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            LoginError::Invalid(_) => StatusCode::BAD_REQUEST,
+            LoginError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoginError::Unavailable(_) => write!(f, "User store unavailable"),
+            LoginError::Invalid(reason) => write!(f, "{}", reason),
+            LoginError::Internal(_) => write!(f, "Internal Error"),
+        }
+    }
+}
+
+impl From<Error> for LoginError {
+    fn from(err: Error) -> Self {
+        match &err {
+            Error::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _) => {
+                LoginError::Unavailable(err.to_string())
+            }
+            // Diesel does not expose SQLITE_BUSY, only its message.
+            Error::DatabaseError(_, info)
+                if info.message().contains("database is locked")
+                    || info.message().contains("unable to open database") =>
+            {
+                LoginError::Unavailable(err.to_string())
+            }
+            _ => LoginError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for LoginError {
+    fn from(err: diesel::r2d2::PoolError) -> Self {
+        LoginError::Unavailable(err.to_string())
+    }
+}
@@ -1,304 +1,903 @@
-use diesel::{
-    r2d2::{ConnectionManager, Pool},
-    sqlite::SqliteConnection,
-    ExpressionMethods, QueryDsl, RunQueryDsl,
-};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime};
+use diesel::{connection::SimpleConnection, r2d2::Pool, QueryResult};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, sync::Arc};
+use tokio::{sync::Semaphore, task, time};
 use tracing::{info, warn};
 use uuid::Uuid;
 use warp::{http, Rejection};
 
 use crate::{
-    models::{History, User},
-    schema,
+    audit::{self, AuditContext, AuditEntry, AuditEvent, AuditRecord, HistoryPage, HistoryQuery},
+    config::AuditConfig,
+    dbconnection::{DbConnection, DbConnectionManager},
+    loginerror::LoginError,
+    metrics,
+    models::{History, Session, User},
+    passwordhash::{self, PasswordCheck},
+    permissions::{AuthError, Credential, Identity, Permission, Role},
+    problem::{self, Problem},
+    ratelimit::{self, LoginLockout},
+    retention::{Archive, PurgeRule},
 };
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct SimplifiedUser {
     pub login: String,
     pub password: String,
+    /// Defaults to `Role::Reader` for new users and is left as is on update.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+impl fmt::Debug for SimplifiedUser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimplifiedUser")
+            .field("login", &self.login)
+            .field("password", &"***")
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+/// Changes to an existing user; fields left out are kept as they are.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct UserUpdate {
+    pub login: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+impl fmt::Debug for UserUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserUpdate")
+            .field("login", &self.login)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("role", &self.role)
+            .finish()
+    }
+}
+
+/// User representation returned by the API, without any credentials.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PublicUser {
+    pub login: String,
+    pub role: Role,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
+        PublicUser {
+            role: user.role(),
+            login: user.login,
+        }
+    }
+}
+
+/// Freshly issued session; `token` is only ever returned once, at login.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NewSession {
+    pub id: String,
+    pub token: String,
+    pub expires: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub user_agent: Option<String>,
+}
+
+impl From<Session> for SessionInfo {
+    fn from(session: Session) -> Self {
+        SessionInfo {
+            id: session.id,
+            created: session.created,
+            expires: session.expires,
+            last_seen: session.last_seen,
+            user_agent: session.user_agent,
+        }
+    }
 }
 
-pub trait LogMngTrait: Send {
-    fn check_user(&self, user: String, pass: String) -> bool;
-    fn get_users_list(&self) -> Result<Vec<SimplifiedUser>, diesel::result::Error>;
-    fn insert_new_user(&self, new_user: SimplifiedUser) -> bool;
-    fn get_by_login(&self, login: String) -> Option<SimplifiedUser>;
-    fn update_password(&self, new_data: SimplifiedUser) -> bool;
-    fn delete_user(&self, login: String) -> bool;
-
-    fn get_security_key(&self, username: String) -> String;
-    fn check_token(&self, token: String, req: String) -> bool;
-    fn get_history(&self, login: String) -> Result<Vec<History>, diesel::result::Error>;
+#[async_trait]
+pub trait LogMngTrait: Send + Sync {
+    async fn check_user(&self, user: String, pass: String) -> Result<bool, LoginError>;
+    async fn get_users_list(&self) -> Result<Vec<PublicUser>, LoginError>;
+    async fn insert_new_user(&self, new_user: SimplifiedUser) -> Result<bool, LoginError>;
+    async fn get_by_login(&self, login: String) -> Result<Option<PublicUser>, LoginError>;
+    async fn update_user(&self, update: UserUpdate) -> Result<bool, LoginError>;
+    async fn delete_user(&self, login: String) -> Result<bool, LoginError>;
+
+    async fn create_session(
+        &self,
+        login: String,
+        user_agent: Option<String>,
+    ) -> Result<NewSession, LoginError>;
+    async fn check_token(&self, token: String) -> Result<bool, LoginError>;
+    /// Resolves the caller behind `credential`; permissions are checked
+    /// with `Identity::check`.
+    async fn authenticate(&self, credential: Credential) -> Result<Identity, AuthError>;
+    async fn logout(&self, token: String) -> Result<bool, LoginError>;
+    async fn get_sessions(&self, token: String) -> Result<Option<Vec<SessionInfo>>, LoginError>;
+    async fn revoke_session(&self, token: String, session_id: String) -> Result<bool, LoginError>;
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), LoginError>;
+    async fn query_history(&self, query: HistoryQuery) -> Result<HistoryPage, LoginError>;
+    /// Checks out a connection and runs a trivial query on it.
+    async fn ping(&self) -> Result<(), LoginError>;
 }
 
+pub const DEFAULT_SESSION_TTL_HOURS: i64 = 24;
+pub const DEFAULT_POOL_SIZE: u32 = 15;
+pub const DEFAULT_CHECKOUT_TIMEOUT_MS: u64 = 2000;
+
+type DbPool = Pool<DbConnectionManager>;
+
+/// User store in SQLite or Postgres, depending on the database URL.
+///
+/// Diesel calls are blocking, so every operation runs on Tokio's blocking
+/// pool. At most `pool_size` operations run at once; callers waiting longer
+/// than the checkout timeout get `LoginError::Unavailable`.
 #[derive(Clone)]
 pub struct LoginManager {
-    db_pool: Pool<ConnectionManager<SqliteConnection>>,
+    db_pool: DbPool,
+    session_ttl: Duration,
+    limiter: Arc<Semaphore>,
+    checkout_timeout: std::time::Duration,
+    audit: Arc<AuditConfig>,
 }
 
 impl LoginManager {
-    pub fn new(db_url: String) -> Self {
+    pub fn new(db_url: String) -> Result<Self, LoginError> {
+        Self::with_pool_size(db_url, DEFAULT_POOL_SIZE)
+    }
+
+    /// Fails with `LoginError::Unavailable` when the database can not be
+    /// opened within the checkout timeout.
+    pub fn with_pool_size(db_url: String, pool_size: u32) -> Result<Self, LoginError> {
+        let checkout_timeout = std::time::Duration::from_millis(DEFAULT_CHECKOUT_TIMEOUT_MS);
         let pool = Pool::builder()
-            .max_size(15)
-            .build(ConnectionManager::<SqliteConnection>::new(db_url))
-            .unwrap();
-        Self { db_pool: pool }
+            .max_size(pool_size)
+            .connection_timeout(checkout_timeout)
+            .build(DbConnectionManager::new(db_url).with_busy_timeout(checkout_timeout))?;
+        Ok(Self {
+            db_pool: pool,
+            session_ttl: Duration::hours(DEFAULT_SESSION_TTL_HOURS),
+            limiter: Arc::new(Semaphore::new(pool_size as usize)),
+            checkout_timeout,
+            audit: Arc::new(AuditConfig::default()),
+        })
     }
-}
 
-impl LogMngTrait for LoginManager {
-    fn check_user(&self, user: String, pass: String) -> bool {
-        let conn = self
-            .db_pool
-            .get()
-            .unwrap_or_else(|_| panic!("Error connecting to DB"));
-        match User::by_login(user, &conn) {
-            Some(res) => res.password == pass,
-            None => false,
-        }
+    pub fn with_session_ttl(mut self, session_ttl: Duration) -> Self {
+        self.session_ttl = session_ttl;
+        self
     }
 
-    fn get_users_list(&self) -> Result<Vec<SimplifiedUser>, diesel::result::Error> {
-        let res = User::get_list(&self.db_pool.get().unwrap());
-        match res {
-            Ok(res_vec) => {
-                let mut res = Vec::<SimplifiedUser>::new();
-                for (log, pas) in res_vec
-                    .iter()
-                    .map(|x| (x.login.clone(), x.password.clone()))
-                {
-                    res.push(SimplifiedUser {
-                        login: log,
-                        password: pas,
-                    });
+    pub fn with_audit(mut self, audit: AuditConfig) -> Self {
+        self.audit = Arc::new(audit);
+        self
+    }
+
+    pub fn with_checkout_timeout(mut self, checkout_timeout: std::time::Duration) -> Self {
+        self.checkout_timeout = checkout_timeout;
+        self
+    }
+
+    /// Runs `f` with a pooled connection on the blocking thread pool.
+    async fn run<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        T: Send + 'static,
+        E: From<LoginError> + Send + 'static,
+        F: FnOnce(&LoginManager, &DbConnection) -> Result<T, E> + Send + 'static,
+    {
+        let started = std::time::Instant::now();
+        let permit = time::timeout(self.checkout_timeout, self.limiter.clone().acquire_owned())
+            .await
+            .map_err(|_| {
+                warn!("Timed out waiting for a DB connection slot");
+                metrics::record_checkout(started.elapsed(), false);
+                LoginError::Unavailable("connection slot timeout".to_string())
+            })?
+            .map_err(|_| LoginError::Unavailable("user store is closed".to_string()))?;
+        let mngr = self.clone();
+        let result = task::spawn_blocking(move || {
+            let _permit = permit;
+            let conn = mngr.db_pool.get_timeout(mngr.checkout_timeout);
+            metrics::record_checkout(started.elapsed(), conn.is_ok());
+            mngr.record_pool_metrics();
+            let conn = conn.map_err(|err| {
+                warn!("DB connection checkout failed: {}", err);
+                LoginError::from(err)
+            })?;
+            f(&mngr, &conn)
+        })
+        .await
+        .map_err(|err| LoginError::Internal(err.to_string()))?;
+        self.record_pool_metrics();
+        result
+    }
+
+    /// Waits up to `timeout` for running operations, then refuses new ones.
+    /// Returns whether every operation finished in time.
+    pub async fn close(&self, timeout: std::time::Duration) -> bool {
+        let drained = matches!(
+            time::timeout(timeout, self.limiter.acquire_many(self.db_pool.max_size())).await,
+            Ok(Ok(_))
+        );
+        self.limiter.close();
+        drained
+    }
+
+    /// Deletes up to `limit` history rows matched by `rule`, oldest first,
+    /// after appending them to `archive`. Returns how many were deleted.
+    pub async fn purge_history(
+        &self,
+        rule: PurgeRule,
+        limit: i64,
+        archive: Option<Archive>,
+    ) -> Result<usize, LoginError> {
+        self.run(move |_, conn| {
+            let rows = match &rule {
+                PurgeRule::OlderThan(before) => History::older_than(conn, *before, limit)?,
+                PurgeRule::Beyond { login, keep } => {
+                    match History::first_beyond(conn, login, *keep)? {
+                        Some(last) => History::through(conn, &last, limit)?,
+                        None => Vec::new(),
+                    }
                 }
-                Ok(res)
+            };
+            if rows.is_empty() {
+                return Ok(0);
             }
-            Err(err) => Err(err),
-        }
+            if let Some(archive) = &archive {
+                archive.append(&rows).map_err(|err| {
+                    LoginError::Internal(format!("Can not archive history: {}", err))
+                })?;
+            }
+            Ok(History::delete_ids(
+                conn,
+                rows.into_iter().map(|x| x.id).collect(),
+            )?)
+        })
+        .await
     }
-    fn insert_new_user(&self, new_user: SimplifiedUser) -> bool {
-        User::insert_new_user(
-            &self.db_pool.get().unwrap(),
-            User {
-                login: new_user.login,
-                password: new_user.password,
-                token: Uuid::new_v4().to_string(),
-            },
-        )
-    }
-    fn get_by_login(&self, login: String) -> Option<SimplifiedUser> {
-        if let Some(user) = User::by_login(login, &self.db_pool.get().unwrap()) {
-            Some(SimplifiedUser {
-                login: user.login,
-                password: user.password,
+
+    /// Every login with at least one history row.
+    pub async fn history_logins(&self) -> Result<Vec<String>, LoginError> {
+        self.run(|_, conn| Ok(History::logins(conn)?)).await
+    }
+
+    fn record_pool_metrics(&self) {
+        let state = self.db_pool.state();
+        let in_flight = self.db_pool.max_size() as usize - self.limiter.available_permits();
+        metrics::record_pool(in_flight, state.connections, state.idle_connections);
+    }
+
+    /// Ends every session of `login` and returns how many there were, or
+    /// `None` when there is no such user.
+    pub async fn revoke_all_sessions(&self, login: String) -> Result<Option<usize>, LoginError> {
+        self.run(move |_, conn| {
+            conn.transaction(|| {
+                if User::by_login(login.clone(), conn)?.is_none() {
+                    return Ok(None);
+                }
+                Ok(Some(Session::delete_by_login(conn, login)?))
             })
+        })
+        .await
+    }
+
+    /// Looks up the session behind `token`, dropping it if it has expired.
+    fn find_session(&self, conn: &DbConnection, token: &str) -> QueryResult<Option<Session>> {
+        let session = match Session::by_token_hash(conn, hash_token(token))? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        if session.expires > chrono::Utc::now().naive_utc() {
+            Ok(Some(session))
         } else {
-            None
+            info!("Session {} of {} expired", session.id, session.login);
+            Session::delete(conn, session.id, session.login)?;
+            Ok(None)
         }
     }
 
-    fn update_password(&self, new_data: SimplifiedUser) -> bool {
-        User::update_user_password(
-            &self.db_pool.get().unwrap(),
-            User {
-                login: new_data.login,
-                password: new_data.password,
-                token: Uuid::new_v4().to_string(),
-            },
-        )
-    }
-
-    fn delete_user(&self, login: String) -> bool {
-        let dsl_filter = schema::users::dsl::users.filter(schema::users::login.eq(login));
-        let res = diesel::delete(dsl_filter).execute(&self.db_pool.get().unwrap());
-        res.is_ok()
-    }
-
-    fn get_security_key(&self, username: String) -> String {
-        let conn = self
-            .db_pool
-            .get()
-            .unwrap_or_else(|_| panic!("Error connecting to DB"));
-        let tmp_usr = User::by_login(username, &conn).unwrap();
-        tmp_usr.token
-    }
-    fn check_token(&self, token: String, req: String) -> bool {
-        let conn = self
-            .db_pool
-            .get()
-            .unwrap_or_else(|_| panic!("Error connecting to DB"));
-        let res = User::by_token(token, &conn);
-        if let Some(val) = res {
-            let elem = History {
+    fn touch_session(&self, conn: &DbConnection, session: &Session) -> QueryResult<()> {
+        Session::touch(conn, session.id.clone(), chrono::Utc::now().naive_utc())
+    }
+}
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+}
+
+/// Only a SHA-256 digest of each token is stored, so a leaked database
+/// does not hand out live sessions.
+pub(crate) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn hash_password(password: &str) -> Result<String, LoginError> {
+    passwordhash::hash_password(password).map_err(|err| {
+        warn!("Password hashing failed: {}", err);
+        LoginError::Internal(err.to_string())
+    })
+}
+
+#[async_trait]
+impl LogMngTrait for LoginManager {
+    async fn check_user(&self, user: String, pass: String) -> Result<bool, LoginError> {
+        self.run(move |_, conn| {
+            let record = match User::by_login(user, conn)? {
+                Some(res) => res,
+                None => return Ok(false),
+            };
+            Ok(
+                match passwordhash::verify_password(&pass, &record.password_hash) {
+                    PasswordCheck::Valid => true,
+                    PasswordCheck::NeedsRehash => {
+                        // The password was correct; a failed rehash is retried next login.
+                        if let Ok(hash) = hash_password(&pass) {
+                            info!("Rehashing password for {}", record.login);
+                            if let Err(err) = User::update_user_password(conn, record.login, hash) {
+                                warn!("Password rehash failed: {}", err);
+                            }
+                        }
+                        true
+                    }
+                    PasswordCheck::Invalid => false,
+                },
+            )
+        })
+        .await
+    }
+
+    async fn get_users_list(&self) -> Result<Vec<PublicUser>, LoginError> {
+        let res = self
+            .run(|_, conn| User::get_list(conn).map_err(LoginError::from))
+            .await?;
+        Ok(res.into_iter().map(PublicUser::from).collect())
+    }
+    async fn insert_new_user(&self, new_user: SimplifiedUser) -> Result<bool, LoginError> {
+        self.run(move |_, conn| {
+            let password_hash = hash_password(&new_user.password)?;
+            Ok(User::insert_new_user(
+                conn,
+                User {
+                    login: new_user.login,
+                    password_hash,
+                    role: new_user.role.unwrap_or_default().as_str().to_string(),
+                },
+            )?)
+        })
+        .await
+    }
+    async fn get_by_login(&self, login: String) -> Result<Option<PublicUser>, LoginError> {
+        self.run(move |_, conn| Ok(User::by_login(login, conn)?.map(PublicUser::from)))
+            .await
+    }
+
+    async fn update_user(&self, update: UserUpdate) -> Result<bool, LoginError> {
+        self.run(move |_, conn| {
+            let password_hash = update.password.as_deref().map(hash_password).transpose()?;
+            conn.transaction(|| {
+                if let Some(role) = update.role {
+                    if !User::update_role(conn, update.login.clone(), role)? {
+                        return Ok(false);
+                    }
+                }
+                match password_hash {
+                    Some(hash) => Ok(User::update_user_password(conn, update.login, hash)?),
+                    None => Ok(User::by_login(update.login, conn)?.is_some()),
+                }
+            })
+        })
+        .await
+    }
+
+    async fn delete_user(&self, login: String) -> Result<bool, LoginError> {
+        self.run(move |_, conn| {
+            conn.transaction(|| {
+                Session::delete_by_login(conn, login.clone())?;
+                Ok(User::delete(conn, login)?)
+            })
+        })
+        .await
+    }
+
+    async fn create_session(
+        &self,
+        login: String,
+        user_agent: Option<String>,
+    ) -> Result<NewSession, LoginError> {
+        self.run(move |mngr, conn| {
+            let token = generate_token();
+            let now = chrono::Utc::now().naive_utc();
+            let session = Session {
                 id: Uuid::new_v4().to_string(),
-                login: val.login,
-                request: req,
-                tms: chrono::Utc::now().naive_utc(),
+                token_hash: hash_token(&token),
+                login,
+                created: now,
+                expires: now + mngr.session_ttl,
+                last_seen: now,
+                user_agent,
             };
-            History::add_element(&conn, elem);
-            true
-        } else {
-            false
-        }
+            let new_session = NewSession {
+                id: session.id.clone(),
+                token,
+                expires: session.expires,
+            };
+            Session::insert(conn, session)?;
+            Ok(new_session)
+        })
+        .await
+    }
+    async fn check_token(&self, token: String) -> Result<bool, LoginError> {
+        self.run(move |mngr, conn| match mngr.find_session(conn, &token)? {
+            Some(session) => {
+                mngr.touch_session(conn, &session)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        })
+        .await
+    }
+    async fn authenticate(&self, credential: Credential) -> Result<Identity, AuthError> {
+        self.run(move |mngr, conn| {
+            let login = match credential {
+                Credential::Token(token) => {
+                    let session = mngr
+                        .find_session(conn, &token)
+                        .map_err(LoginError::from)?
+                        .ok_or(AuthError::InvalidToken)?;
+                    mngr.touch_session(conn, &session)
+                        .map_err(LoginError::from)?;
+                    session.login
+                }
+                Credential::ClientCert(login) => login,
+            };
+            // The user may have been deleted since the session was issued.
+            let user = User::by_login(login, conn)
+                .map_err(LoginError::from)?
+                .ok_or(AuthError::InvalidToken)?;
+            Ok(Identity {
+                role: user.role(),
+                login: user.login,
+            })
+        })
+        .await
+    }
+    async fn logout(&self, token: String) -> Result<bool, LoginError> {
+        self.run(move |mngr, conn| match mngr.find_session(conn, &token)? {
+            Some(session) => Ok(Session::delete(conn, session.id, session.login)?),
+            None => Ok(false),
+        })
+        .await
+    }
+    async fn get_sessions(&self, token: String) -> Result<Option<Vec<SessionInfo>>, LoginError> {
+        self.run(move |mngr, conn| {
+            let session = match mngr.find_session(conn, &token)? {
+                Some(session) => session,
+                None => return Ok(None),
+            };
+            let now = chrono::Utc::now().naive_utc();
+            let list = Session::get_by_login(conn, session.login)?;
+            Ok(Some(
+                list.into_iter()
+                    .filter(|x| x.expires > now)
+                    .map(SessionInfo::from)
+                    .collect(),
+            ))
+        })
+        .await
     }
-    fn get_history(&self, login: String) -> Result<Vec<History>, diesel::result::Error> {
-        let conn = self
-            .db_pool
-            .get()
-            .unwrap_or_else(|_| panic!("Error connecting to DB"));
-        History::get_by_login(&conn, login)
+    async fn revoke_session(&self, token: String, session_id: String) -> Result<bool, LoginError> {
+        self.run(move |mngr, conn| match mngr.find_session(conn, &token)? {
+            Some(session) => Ok(Session::delete(conn, session_id, session.login)?),
+            None => Ok(false),
+        })
+        .await
+    }
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), LoginError> {
+        self.run(move |mngr, conn| Ok(History::add_element(conn, entry.to_history(&mngr.audit))?))
+            .await
+    }
+    async fn query_history(&self, query: HistoryQuery) -> Result<HistoryPage, LoginError> {
+        let cursor = query.decoded_cursor()?;
+        self.run(move |_, conn| {
+            let history = History::query(conn, &query, cursor)?;
+            Ok(query.make_page(history.into_iter().map(AuditRecord::from).collect()))
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<(), LoginError> {
+        self.run(|_, conn| Ok(conn.batch_execute("SELECT 1")?))
+            .await
     }
 }
 
 pub async fn check_login_data(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+    lockout: Arc<LoginLockout>,
     log: String,
     pas: String,
+    user_agent: Option<String>,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, Rejection> {
-    if mngr.check_user(log.clone(), pas.clone()) {
-        info!("Got user {}:{}", log, pas);
+    let event = AuditEvent::Login { login: log.clone() };
+    let result = async {
+        if let Err(remaining) = lockout.check(&log) {
+            info!("Rejected login of locked user {}", log);
+            metrics::record_login(false);
+            return Err(problem::reject(ratelimit::too_many_requests(
+                "login_locked",
+                "Too many failed logins, try again later",
+                remaining,
+            )));
+        }
+        if !mngr
+            .check_user(log.clone(), pas)
+            .await
+            .map_err(problem::reject)?
+        {
+            info!("Failed login of {}", log);
+            metrics::record_login(false);
+            if let Some(lock) = lockout.record_failure(&log) {
+                warn!(
+                    "Locked user {} for {}s after failed logins",
+                    log,
+                    lock.as_secs()
+                );
+            }
+            return Err(problem::reject(Problem::new(
+                http::StatusCode::UNAUTHORIZED,
+                "invalid_credentials",
+                "Wrong login or password",
+            )));
+        }
+        info!("User {} logged in", log);
+        lockout.record_success(&log);
+        metrics::record_login(true);
+        let session = mngr
+            .create_session(log.clone(), user_agent)
+            .await
+            .map_err(problem::reject)?;
         Ok(warp::reply::with_status(
-            warp::reply::with_header(warp::reply(), "token", mngr.get_security_key(log)),
+            warp::reply::with_header(
+                warp::reply::with_header(warp::reply(), "token", session.token),
+                "session-id",
+                session.id,
+            ),
             http::StatusCode::OK,
         ))
-    } else {
-        info!("No user {}:{}", log, pas);
-        Err(warp::reject())
     }
+    .await;
+    audit::record_as(&mngr, context, log, None, event, result).await
 }
 
-pub async fn get_users_list(
-    mngr: impl LogMngTrait + Clone + Sync,
+pub async fn logout(
+    mngr: impl LogMngTrait + Clone,
     token: String,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, Rejection> {
-    if !mngr.check_token(token, "Get users list".to_string()) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&"Wrong token".to_string()),
-            http::StatusCode::FORBIDDEN,
-        ));
+    let credential = Credential::Token(token.clone());
+    let identity = mngr.authenticate(credential.clone()).await;
+    let result = async {
+        identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        if mngr.logout(token).await.map_err(problem::reject)? {
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Success!".to_string()),
+                http::StatusCode::NO_CONTENT,
+            ))
+        } else {
+            Err(problem::reject(Problem::internal("Could not end session")))
+        }
     }
+    .await;
+    audit::record(
+        &mngr,
+        context,
+        &credential,
+        &identity,
+        AuditEvent::Logout,
+        result,
+    )
+    .await
+}
 
-    let res = mngr.get_users_list();
-    match res {
-        Ok(users_vec) => Ok(warp::reply::with_status(
-            warp::reply::json(&users_vec),
-            http::StatusCode::OK,
-        )),
-        Err(err) => {
-            warn!("Error while getting user list {}", err);
-            Err(warp::reject())
+pub async fn get_sessions_list(
+    mngr: impl LogMngTrait + Clone,
+    token: String,
+    context: AuditContext,
+) -> Result<impl warp::Reply, Rejection> {
+    let credential = Credential::Token(token.clone());
+    let identity = mngr.authenticate(credential.clone()).await;
+    let result = async {
+        identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        match mngr.get_sessions(token).await.map_err(problem::reject)? {
+            Some(sessions) => Ok(warp::reply::with_status(
+                warp::reply::json(&sessions),
+                http::StatusCode::OK,
+            )),
+            // The session ended between the token check and the lookup.
+            None => Err(problem::reject(AuthError::InvalidToken)),
         }
     }
+    .await;
+    audit::record(
+        &mngr,
+        context,
+        &credential,
+        &identity,
+        AuditEvent::ListSessions,
+        result,
+    )
+    .await
 }
 
-pub async fn insert_user(
-    mngr: impl LogMngTrait + Clone + Sync,
-    new_user: SimplifiedUser,
+pub async fn delete_certain_session(
+    mngr: impl LogMngTrait + Clone,
+    session_id: String,
     token: String,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, Rejection> {
-    if !mngr.check_token(token, format!("Insert user {:?}", new_user)) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&"Wrong token".to_string()),
-            http::StatusCode::FORBIDDEN,
-        ));
+    let credential = Credential::Token(token.clone());
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::RevokeSession {
+        session_id: session_id.clone(),
+    };
+    let result = async {
+        identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        if mngr
+            .revoke_session(token, session_id.clone())
+            .await
+            .map_err(problem::reject)?
+        {
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Success!".to_string()),
+                http::StatusCode::NO_CONTENT,
+            ))
+        } else {
+            Err(problem::reject(Problem::new(
+                http::StatusCode::NOT_FOUND,
+                "session_not_found",
+                format!("No session {}", session_id),
+            )))
+        }
     }
-    if mngr.insert_new_user(new_user) {
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
+}
+
+pub async fn get_users_list(
+    mngr: impl LogMngTrait + Clone,
+    credential: Credential,
+    context: AuditContext,
+) -> Result<impl warp::Reply, Rejection> {
+    let identity = mngr.authenticate(credential.clone()).await;
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::ReadUsers, None)
+            .map_err(problem::reject)?;
+        let users_vec = mngr.get_users_list().await.map_err(problem::reject)?;
         Ok(warp::reply::with_status(
-            warp::reply::json(&"Success!".to_string()),
+            warp::reply::json(&users_vec),
             http::StatusCode::OK,
         ))
-    } else {
-        Err(warp::reject())
     }
+    .await;
+    audit::record(
+        &mngr,
+        context,
+        &credential,
+        &identity,
+        AuditEvent::ListUsers,
+        result,
+    )
+    .await
 }
 
-pub async fn get_certain_user(
-    mngr: impl LogMngTrait + Clone + Sync,
-    user_id: String,
-    token: String,
+pub async fn insert_user(
+    mngr: impl LogMngTrait + Clone,
+    new_user: SimplifiedUser,
+    credential: Credential,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, Rejection> {
-    if !mngr.check_token(token, format!("Get user with id{}", user_id)) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&"Wrong token".to_string()),
-            http::StatusCode::FORBIDDEN,
-        ));
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::CreateUser(new_user.clone());
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::WriteUsers, None)
+            .map_err(problem::reject)?;
+        let login = new_user.login.clone();
+        if mngr
+            .insert_new_user(new_user)
+            .await
+            .map_err(problem::reject)?
+        {
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Success!".to_string()),
+                http::StatusCode::OK,
+            ))
+        } else {
+            Err(problem::reject(Problem::new(
+                http::StatusCode::CONFLICT,
+                "duplicate_user",
+                format!("User {} already exists", login),
+            )))
+        }
     }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
+}
 
-    let res = mngr.get_by_login(user_id);
-    if res.is_some() {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&res.unwrap()),
-            http::StatusCode::OK,
-        ))
-    } else {
-        Err(warp::reject())
+pub async fn get_certain_user(
+    mngr: impl LogMngTrait + Clone,
+    user_id: String,
+    credential: Credential,
+    context: AuditContext,
+) -> Result<impl warp::Reply, Rejection> {
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::GetUser {
+        login: user_id.clone(),
+    };
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::ReadUsers, Some(&user_id))
+            .map_err(problem::reject)?;
+        if let Some(user) = mngr
+            .get_by_login(user_id.clone())
+            .await
+            .map_err(problem::reject)?
+        {
+            Ok(warp::reply::with_status(
+                warp::reply::json(&user),
+                http::StatusCode::OK,
+            ))
+        } else {
+            Err(problem::reject(user_not_found(&user_id)))
+        }
     }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
 }
 
 pub async fn update_certain_user(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
     user_id: String,
-    new_data: SimplifiedUser,
-    token: String,
+    new_data: UserUpdate,
+    credential: Credential,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, Rejection> {
-    if !mngr.check_token(
-        token,
-        format!("Update user {} with {:?}", user_id, new_data),
-    ) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&"Wrong token".to_string()),
-            http::StatusCode::FORBIDDEN,
-        ));
-    }
-
-    if new_data.login != user_id {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&"login mismatch!".to_string()),
-            http::StatusCode::BAD_REQUEST,
-        ))
-    } else if mngr.update_password(new_data) {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&"Success!".to_string()),
-            http::StatusCode::OK,
-        ))
-    } else {
-        Err(warp::reject())
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::UpdateUser {
+        login: user_id.clone(),
+        user: new_data.clone(),
+    };
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        // Changing a role is never self-service.
+        let owner = if new_data.role.is_none() {
+            Some(user_id.as_str())
+        } else {
+            None
+        };
+        identity
+            .check(Permission::WriteUsers, owner)
+            .map_err(problem::reject)?;
+        if new_data.login != user_id {
+            Err(problem::reject(Problem::new(
+                http::StatusCode::BAD_REQUEST,
+                "login_mismatch",
+                "login mismatch!",
+            )))
+        } else if mngr.update_user(new_data).await.map_err(problem::reject)? {
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Success!".to_string()),
+                http::StatusCode::OK,
+            ))
+        } else {
+            Err(problem::reject(user_not_found(&user_id)))
+        }
     }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
 }
 
 pub async fn delete_certain_user(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
     user_id: String,
-    token: String,
+    credential: Credential,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, Rejection> {
-    if !mngr.check_token(token, format!("Delete user {:?}", user_id)) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&"Wrong token".to_string()),
-            http::StatusCode::FORBIDDEN,
-        ));
-    }
-
-    if mngr.delete_user(user_id) {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&"Success!".to_string()),
-            http::StatusCode::NO_CONTENT,
-        ))
-    } else {
-        Err(warp::reject())
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::DeleteUser {
+        login: user_id.clone(),
+    };
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::WriteUsers, None)
+            .map_err(problem::reject)?;
+        if mngr
+            .delete_user(user_id.clone())
+            .await
+            .map_err(problem::reject)?
+        {
+            Ok(warp::reply::with_status(
+                warp::reply::json(&"Success!".to_string()),
+                http::StatusCode::NO_CONTENT,
+            ))
+        } else {
+            Err(problem::reject(user_not_found(&user_id)))
+        }
     }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
 }
 
-pub async fn get_history_for_user(
-    mngr: impl LogMngTrait + Clone + Sync,
-    user_id: String,
+pub async fn get_history(
+    mngr: impl LogMngTrait + Clone,
+    query: HistoryQuery,
+    credential: Credential,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, Rejection> {
-    match mngr.get_history(user_id) {
-        Ok(res) => Ok(warp::reply::with_status(
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::GetHistory(query.clone());
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::ReadHistory, query.login.as_deref())
+            .map_err(problem::reject)?;
+        let res = mngr.query_history(query).await.map_err(problem::reject)?;
+        Ok(warp::reply::with_status(
             warp::reply::json(&res),
             http::StatusCode::OK,
-        )),
-        Err(_) => Err(warp::reject()),
+        ))
     }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
+}
+
+fn user_not_found(login: &str) -> Problem {
+    Problem::new(
+        http::StatusCode::NOT_FOUND,
+        "user_not_found",
+        format!("No user {}", login),
+    )
 }
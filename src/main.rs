@@ -1,55 +1,244 @@
-use std::env;
+use std::process;
 
+use clap::Parser;
+use rust_test_project::bootstrap::{self, Bootstrap};
+use rust_test_project::cli;
+use rust_test_project::config::{CliArgs, Config, Profile};
+use rust_test_project::health;
 use rust_test_project::loginmanager::LoginManager;
-use rust_test_project::mongodbprovider::{self, MongoConnectionParameters, MongoDBProvider};
-use rust_test_project::routes::{
-    delete_certain_user, get_certain_user, get_filter_fcn, get_history_fcn, get_users_fcn,
-    insert_filter_fcn, login_filter_fcn, post_user_fcn, update_certain_user,
+use rust_test_project::metrics;
+use rust_test_project::migrations;
+use rust_test_project::mongodbprovider::{MongoDBProvider, MongoDBProviderTrait};
+use rust_test_project::problem;
+use rust_test_project::ratelimit::Limits;
+use rust_test_project::retention;
+use rust_test_project::routes;
+use rust_test_project::tls;
+use tokio::{
+    signal,
+    sync::oneshot,
+    time::{self, Instant},
 };
-use tracing::info;
+use tracing::{error, info, warn};
 use warp::Filter;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt().init();
-    info!("Program started");
-    let mongo_address: String = mongodbprovider::get_db_address_from_env().unwrap();
-    let db_name = env::var("MONGO_INITDB_ROOT_USERNAME").unwrap_or_else(|_| "".to_string());
-    let db_pass = env::var("MONGO_INITDB_ROOT_PASSWORD").unwrap_or_else(|_| "".to_string());
-    let db_provider = MongoDBProvider::new(MongoConnectionParameters {
-        address: mongo_address,
-        port: 27017,
-        user_name: db_name,
-        password: db_pass,
-    })
-    .await;
+    let mut args = CliArgs::parse();
+    if args.command.is_some() {
+        // Keep stdout for the command's own report.
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .init();
+    } else {
+        tracing_subscriber::fmt().init();
+    }
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            eprintln!("Error: {}", err);
+            process::exit(2);
+        }
+    };
+    if args.print_config {
+        print!("{}", config.redacted().to_toml());
+        return;
+    }
+    info!("Program started with config {:?}", config.redacted());
+    if let Err(err) = migrations::prepare_database(&config.database.url, !args.no_migrate) {
+        error!("{}", err);
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+    if args.migrate_only {
+        return;
+    }
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let login_manager = LoginManager::new(database_url);
+    let login_manager = match LoginManager::with_pool_size(
+        config.database.url.clone(),
+        config.database.pool_size,
+    ) {
+        Ok(mngr) => mngr
+            .with_session_ttl(chrono::Duration::hours(config.database.session_ttl_hours))
+            .with_audit(config.audit.clone()),
+        Err(err) => {
+            error!("Can not open user database: {:?}", err);
+            eprintln!("Error: can not open user database: {}", err);
+            process::exit(1);
+        }
+    };
+    if let Some(command) = args.command.take() {
+        if let Err(err) = cli::run(command, &login_manager, &mut std::io::stdout()).await {
+            error!("{}", err);
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    if let Err(err) = bootstrap_users(&login_manager, &args, &config).await {
+        error!("{}", err);
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+    let db_provider = MongoDBProvider::new(config.mongo_parameters()).await;
+    // Not fatal: `/readyz` keeps reporting Mongo until it comes up.
+    let mongo = health::check(db_provider.ping()).await;
+    if let Some(err) = mongo.error {
+        warn!("MongoDB is not reachable yet: {}", err);
+    }
     info!("Creating routes");
-    let db_provider_clone = db_provider.clone();
-    let insert_route = insert_filter_fcn(db_provider.clone(), login_manager.clone()).await;
-    let get_route = get_filter_fcn(db_provider_clone.clone(), login_manager.clone()).await;
-    let log_route = login_filter_fcn(login_manager.clone()).await;
-    let users_get_route = get_users_fcn(login_manager.clone()).await;
-    let users_insert_route = post_user_fcn(login_manager.clone()).await;
-    let user_get_route = get_certain_user(login_manager.clone()).await;
-    let user_update_route = update_certain_user(login_manager.clone()).await;
-    let user_delete_route = delete_certain_user(login_manager.clone()).await;
-    let get_history_route = get_history_fcn(login_manager.clone()).await;
-    let data_path = warp::path("data");
-    let data_path_routes = data_path
-        .and(insert_route)
-        .or(data_path.and(get_route))
-        .or(log_route)
-        .or(users_get_route)
-        .or(users_insert_route)
-        .or(user_get_route)
-        .or(user_update_route)
-        .or(user_delete_route)
-        .or(get_history_route);
+    let routes = routes::api(
+        db_provider.clone(),
+        login_manager.clone(),
+        Limits::new(&config.rate_limit),
+    )
+    .await
+    .recover(problem::handle_rejection)
+    .with(metrics::track_requests());
     info!("Starting server");
-    warp::serve(data_path_routes)
-        .run(([0, 0, 0, 0], 3030))
+    let (stop, stopped) = oneshot::channel::<()>();
+    let stopped = async {
+        stopped.await.ok();
+    };
+    let (address, mut server) = if config.tls.cert_path.is_some() {
+        let (tls_config, resolver) = match tls::server_config(&config.tls) {
+            Ok(tls) => tls,
+            Err(err) => {
+                error!("{}", err);
+                eprintln!("Error: {}", err);
+                process::exit(1);
+            }
+        };
+        resolver.watch(tls::RELOAD_INTERVAL);
+        let bound = tls::bind_with_graceful_shutdown(
+            config.bind_address().into(),
+            tls_config,
+            routes,
+            stopped,
+        )
         .await;
+        match bound {
+            Ok((address, server)) => (address, tokio::spawn(server)),
+            Err(err) => {
+                error!("Can not listen on {:?}: {}", config.bind_address(), err);
+                eprintln!("Error: can not listen: {}", err);
+                process::exit(1);
+            }
+        }
+    } else {
+        let (address, server) =
+            warp::serve(routes).bind_with_graceful_shutdown(config.bind_address(), stopped);
+        (address, tokio::spawn(server))
+    };
+    info!(
+        "Listening on {}://{}",
+        if config.tls.cert_path.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        address
+    );
+    let purge = retention::spawn(login_manager.clone(), config.retention.clone());
+    tokio::select! {
+        _ = shutdown_signal() => {}
+        result = &mut server => {
+            // Only a panic ends the server before the shutdown signal.
+            error!("Server stopped unexpectedly: {:?}", result);
+            process::exit(1);
+        }
+    }
+
+    // Stop accepting connections, then give in-flight requests until the
+    // deadline to finish, including their history writes.
+    let deadline = Instant::now() + config.shutdown_timeout();
+    info!(
+        "Shutting down, draining requests for {:?}",
+        config.shutdown_timeout()
+    );
+    stop.send(()).ok();
+    if time::timeout_at(deadline, &mut server).await.is_err() {
+        warn!("Shutdown deadline passed, dropping in-flight requests");
+        server.abort();
+    }
+    // A batch already handed to the store still completes, archive and
+    // delete together, and is waited for by `close`.
+    if let Some(purge) = purge {
+        purge.abort();
+    }
+    if !login_manager
+        .close(deadline.saturating_duration_since(Instant::now()))
+        .await
+    {
+        warn!("User store operations were still running at shutdown");
+    }
+    db_provider.shutdown().await;
+    info!("Shutdown complete");
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = signal::ctrl_c().await {
+            error!("Can not listen for SIGINT: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("Can not listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Creates an administrator when the user store has none and checks that
+/// none of the old seeded accounts is still usable.
+async fn bootstrap_users(
+    mngr: &LoginManager,
+    args: &CliArgs,
+    config: &Config,
+) -> Result<(), String> {
+    let bootstrap =
+        bootstrap::bootstrap_admin(mngr, args.admin_login.clone(), args.admin_password.clone())
+            .await
+            .map_err(|err| format!("can not create the bootstrap administrator: {}", err))?;
+    if let Bootstrap::Generated { login, password } = bootstrap {
+        // Printed once and never logged.
+        eprintln!(
+            "Created administrator {} with password {}\nIt will not be shown again.",
+            login, password
+        );
+    }
+
+    let logins = bootstrap::default_credentials_in_use(mngr)
+        .await
+        .map_err(|err| format!("can not check for default credentials: {}", err))?;
+    if logins.is_empty() {
+        Ok(())
+    } else if config.server.profile == Profile::Production {
+        Err(format!(
+            "users {} still have their default passwords",
+            logins.join(", ")
+        ))
+    } else {
+        warn!(
+            "Users {} still have their default passwords",
+            logins.join(", ")
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,230 @@
+use std::{future::Future, time::Instant};
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tracing::warn;
+use warp::{http, Rejection};
+
+use crate::{dataerror::DataError, loginerror::LoginError, retention::PurgeReport, routes};
+
+/// Every metric of the service, exported in the Prometheus text format by
+/// the `/metrics` route.
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    mongo_duration: HistogramVec,
+    pool_connections: IntGaugeVec,
+    pool_in_flight: IntGauge,
+    checkout_wait: HistogramVec,
+    logins: IntCounterVec,
+    purge_runs: IntCounterVec,
+    purged_rows: IntCounterVec,
+    purge_duration: HistogramVec,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP request latency by route and status",
+            ),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let mongo_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "mongo_operation_duration_seconds",
+                "Duration of MongoDB operations",
+            ),
+            &["operation", "result"],
+        )
+        .unwrap();
+        let pool_connections = IntGaugeVec::new(
+            Opts::new(
+                "user_store_pool_connections",
+                "Open user store connections, refreshed on every checkout",
+            ),
+            &["state"],
+        )
+        .unwrap();
+        let pool_in_flight = IntGauge::new(
+            "user_store_operations_in_flight",
+            "User store operations holding a connection slot",
+        )
+        .unwrap();
+        let checkout_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "user_store_checkout_wait_seconds",
+                "Time spent waiting for a user store connection",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "Login attempts by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let purge_runs = IntCounterVec::new(
+            Opts::new("history_purge_runs_total", "History purge runs by result"),
+            &["result"],
+        )
+        .unwrap();
+        let purged_rows = IntCounterVec::new(
+            Opts::new(
+                "history_purged_rows_total",
+                "History entries deleted by the retention policy",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let purge_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "history_purge_duration_seconds",
+                "Duration of history purge runs",
+            ),
+            &["result"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(mongo_duration.clone())).unwrap();
+        registry
+            .register(Box::new(pool_connections.clone()))
+            .unwrap();
+        registry.register(Box::new(pool_in_flight.clone())).unwrap();
+        registry.register(Box::new(checkout_wait.clone())).unwrap();
+        registry.register(Box::new(logins.clone())).unwrap();
+        registry.register(Box::new(purge_runs.clone())).unwrap();
+        registry.register(Box::new(purged_rows.clone())).unwrap();
+        registry.register(Box::new(purge_duration.clone())).unwrap();
+        Metrics {
+            registry,
+            http_requests,
+            http_duration,
+            mongo_duration,
+            pool_connections,
+            pool_in_flight,
+            checkout_wait,
+            logins,
+            purge_runs,
+            purged_rows,
+            purge_duration,
+        }
+    }
+}
+
+/// Current value of every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        warn!("Can not encode metrics: {}", err);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Counts every response of the wrapped filter, including recovered
+/// rejections, so it belongs after `.recover(problem::handle_rejection)`.
+pub fn track_requests() -> warp::log::Log<impl Fn(warp::log::Info<'_>) + Clone> {
+    warp::log::custom(|info: warp::log::Info<'_>| {
+        let status = info.status();
+        let labels = [
+            routes::route_label(info.path()),
+            info.method().as_str(),
+            status.as_str(),
+        ];
+        METRICS.http_requests.with_label_values(&labels).inc();
+        METRICS
+            .http_duration
+            .with_label_values(&labels)
+            .observe(info.elapsed().as_secs_f64());
+    })
+}
+
+/// Awaits a MongoDB operation and records how long it took.
+pub async fn time_mongo<T>(
+    operation: &str,
+    fut: impl Future<Output = Result<T, DataError>>,
+) -> Result<T, DataError> {
+    let started = Instant::now();
+    let result = fut.await;
+    let outcome = match &result {
+        Ok(_) => "ok",
+        Err(DataError::NotFound) => "not_found",
+        Err(_) => "error",
+    };
+    METRICS
+        .mongo_duration
+        .with_label_values(&[operation, outcome])
+        .observe(started.elapsed().as_secs_f64());
+    result
+}
+
+/// Records a user store checkout that waited `waited` for its slot.
+pub fn record_checkout(waited: std::time::Duration, ok: bool) {
+    METRICS
+        .checkout_wait
+        .with_label_values(&[if ok { "ok" } else { "timeout" }])
+        .observe(waited.as_secs_f64());
+}
+
+/// Snapshot of the user store pool, taken around every operation.
+pub fn record_pool(in_flight: usize, connections: u32, idle: u32) {
+    METRICS.pool_in_flight.set(in_flight as i64);
+    METRICS
+        .pool_connections
+        .with_label_values(&["idle"])
+        .set(idle as i64);
+    METRICS
+        .pool_connections
+        .with_label_values(&["in_use"])
+        .set(connections.saturating_sub(idle) as i64);
+}
+
+pub fn record_login(success: bool) {
+    METRICS
+        .logins
+        .with_label_values(&[if success { "success" } else { "failure" }])
+        .inc();
+}
+
+pub fn record_purge(result: &Result<PurgeReport, LoginError>, elapsed: std::time::Duration) {
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    METRICS.purge_runs.with_label_values(&[outcome]).inc();
+    METRICS
+        .purge_duration
+        .with_label_values(&[outcome])
+        .observe(elapsed.as_secs_f64());
+    if let Ok(report) = result {
+        METRICS
+            .purged_rows
+            .with_label_values(&["age"])
+            .inc_by(report.expired as u64);
+        METRICS
+            .purged_rows
+            .with_label_values(&["rows_per_user"])
+            .inc_by(report.trimmed as u64);
+    }
+}
+
+pub async fn metrics_handler() -> Result<impl warp::Reply, Rejection> {
+    Ok(warp::reply::with_header(
+        render(),
+        http::header::CONTENT_TYPE,
+        prometheus::TEXT_FORMAT,
+    ))
+}
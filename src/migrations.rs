@@ -0,0 +1,122 @@
+use std::{collections::HashSet, fmt};
+
+use diesel_migrations::{Migration, MigrationConnection};
+use tracing::{info, warn};
+
+use crate::dbconnection::{with_connection, Backend, DbConnection};
+
+mod sqlite {
+    #[derive(EmbedMigrations)]
+    #[embed_migrations_options(migrations_path = "migrations")]
+    struct _Dummy;
+
+    pub(super) fn all() -> &'static [&'static dyn super::Migration] {
+        ALL_MIGRATIONS
+    }
+}
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    #[derive(EmbedMigrations)]
+    #[embed_migrations_options(migrations_path = "migrations_postgres")]
+    struct _Dummy;
+
+    pub(super) fn all() -> &'static [&'static dyn super::Migration] {
+        ALL_MIGRATIONS
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MigrationError {
+    Connection(String),
+    /// The database has migrations applied that this binary does not know,
+    /// so it was most likely migrated by a newer release.
+    SchemaTooNew(Vec<String>),
+    Failed(String),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Connection(err) => write!(f, "can not open database: {}", err),
+            MigrationError::SchemaTooNew(versions) => write!(
+                f,
+                "database schema is newer than this binary, unknown migrations: {}",
+                versions.join(", ")
+            ),
+            MigrationError::Failed(err) => write!(f, "migration failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// Migrations compiled into this binary for `backend`, oldest first.
+pub fn embedded(backend: Backend) -> &'static [&'static dyn Migration] {
+    match backend {
+        Backend::Sqlite => sqlite::all(),
+        #[cfg(feature = "postgres")]
+        Backend::Postgres => postgres::all(),
+        #[cfg(not(feature = "postgres"))]
+        Backend::Postgres => &[],
+    }
+}
+
+/// Versions of the embedded migrations not yet applied to the database.
+pub fn pending(conn: &DbConnection) -> Result<Vec<String>, MigrationError> {
+    let embedded = embedded(conn.backend());
+    let applied = with_connection!(conn, conn => diesel_migrations::setup_database(conn)
+        .and_then(|_| conn.previously_run_migration_versions()))
+    .map_err(|err| MigrationError::Failed(err.to_string()))?;
+
+    let known: HashSet<&str> = embedded.iter().map(|x| x.version()).collect();
+    let mut unknown: Vec<String> = applied
+        .iter()
+        .filter(|x| !known.contains(x.as_str()))
+        .cloned()
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(MigrationError::SchemaTooNew(unknown));
+    }
+    Ok(embedded
+        .iter()
+        .map(|x| x.version().to_string())
+        .filter(|x| !applied.contains(x))
+        .collect())
+}
+
+/// Applies every pending migration, each in its own transaction, and
+/// returns their versions.
+pub fn run(conn: &DbConnection) -> Result<Vec<String>, MigrationError> {
+    let pending = pending(conn)?;
+    let migrations = embedded(conn.backend()).iter().copied();
+    with_connection!(conn, conn => diesel_migrations::run_migrations(
+        conn,
+        migrations,
+        &mut std::io::sink()
+    ))
+    .map_err(|err| MigrationError::Failed(err.to_string()))?;
+    Ok(pending)
+}
+
+/// Startup check of the user store: refuses a schema newer than this
+/// binary and, when `apply` is set, brings an older one up to date.
+pub fn prepare_database(url: &str, apply: bool) -> Result<(), MigrationError> {
+    let conn =
+        DbConnection::establish(url).map_err(|err| MigrationError::Connection(err.to_string()))?;
+    if apply {
+        let applied = run(&conn)?;
+        if applied.is_empty() {
+            info!("Database schema is up to date");
+        } else {
+            info!("Applied migrations {}", applied.join(", "));
+        }
+    } else {
+        let pending = pending(&conn)?;
+        if !pending.is_empty() {
+            warn!("Skipping pending migrations {}", pending.join(", "));
+        }
+    }
+    Ok(())
+}
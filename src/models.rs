@@ -1,6 +1,10 @@
+use crate::audit::{HistoryCursor, HistoryQuery, SortOrder};
+use crate::dbconnection::{with_connection, DbConnection};
+use crate::permissions::Role;
 use crate::schema;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error};
 use serde::{Deserialize, Serialize};
 
 use super::schema::users;
@@ -9,50 +13,110 @@ use super::schema::users::dsl::users as user_dsl;
 use super::schema::history;
 use super::schema::history::dsl::history as history_dsl;
 
+use super::schema::sessions;
+use super::schema::sessions::dsl::sessions as sessions_dsl;
+
 #[derive(Debug, Deserialize, Serialize, Queryable, Insertable, Clone)]
 #[table_name = "users"]
 pub struct User {
     pub login: String,
-    pub password: String,
-    pub token: String,
+    pub password_hash: String,
+    pub role: String,
 }
 impl User {
-    pub fn by_login(login: String, conn: &SqliteConnection) -> Option<Self> {
-        if let Ok(record) = user_dsl.find(login).get_result::<User>(conn) {
-            Some(record)
-        } else {
-            None
-        }
-    }
-    pub fn by_token(token: String, conn: &SqliteConnection) -> Option<Self> {
-        let dsl_filter = schema::users::dsl::users.filter(schema::users::token.eq(token));
-        if let Ok(record) = dsl_filter.first::<User>(conn) {
-            Some(record)
-        } else {
-            None
-        }
+    /// Unknown role names fall back to the least privileged role.
+    pub fn role(&self) -> Role {
+        self.role.parse().unwrap_or_default()
     }
 
-    pub fn get_list(conn: &SqliteConnection) -> Result<Vec<User>, diesel::result::Error> {
-        user_dsl.load::<User>(conn) //expect("Error while loading users list")
+    pub fn by_login(login: String, conn: &DbConnection) -> QueryResult<Option<Self>> {
+        with_connection!(conn, conn => user_dsl.find(login).get_result::<User>(conn).optional())
     }
-    pub fn insert_new_user(conn: &SqliteConnection, new_user: User) -> bool {
-        let res = diesel::insert_into(user_dsl)
+    pub fn get_list(conn: &DbConnection) -> Result<Vec<User>, diesel::result::Error> {
+        with_connection!(conn, conn => user_dsl.load::<User>(conn)) //expect("Error while loading users list")
+    }
+    /// `Ok(false)` when the login is already taken.
+    pub fn insert_new_user(conn: &DbConnection, new_user: User) -> QueryResult<bool> {
+        let res = with_connection!(conn, conn => diesel::insert_into(user_dsl)
             .values(&new_user)
-            .execute(conn);
-        res.is_ok()
-    }
-    pub fn update_user_password(conn: &SqliteConnection, new_user: User) -> bool {
-        let dsl_filter = schema::users::dsl::users.filter(schema::users::login.eq(new_user.login));
-        let res = diesel::update(dsl_filter)
-            .set(schema::users::password.eq(new_user.password))
-            .execute(conn);
-        if let Ok(result) = res {
-            result != 0
-        } else {
-            false
+            .execute(conn));
+        match res {
+            Ok(_) => Ok(true),
+            Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(false),
+            Err(err) => Err(err),
         }
     }
+    pub fn update_user_password(
+        conn: &DbConnection,
+        login: String,
+        password_hash: String,
+    ) -> QueryResult<bool> {
+        let dsl_filter = schema::users::dsl::users.filter(schema::users::login.eq(login));
+        let count = with_connection!(conn, conn => diesel::update(dsl_filter)
+            .set(schema::users::password_hash.eq(password_hash))
+            .execute(conn))?;
+        Ok(count != 0)
+    }
+    pub fn update_role(conn: &DbConnection, login: String, role: Role) -> QueryResult<bool> {
+        let dsl_filter = schema::users::dsl::users.filter(schema::users::login.eq(login));
+        let count = with_connection!(conn, conn => diesel::update(dsl_filter)
+            .set(schema::users::role.eq(role.as_str()))
+            .execute(conn))?;
+        Ok(count != 0)
+    }
+    pub fn delete(conn: &DbConnection, login: String) -> QueryResult<bool> {
+        let dsl_filter = schema::users::dsl::users.filter(schema::users::login.eq(login));
+        let count = with_connection!(conn, conn => diesel::delete(dsl_filter).execute(conn))?;
+        Ok(count != 0)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Queryable, Insertable, Clone)]
+#[table_name = "sessions"]
+pub struct Session {
+    pub id: String,
+    pub token_hash: String,
+    pub login: String,
+    pub created: NaiveDateTime,
+    pub expires: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub user_agent: Option<String>,
+}
+
+impl Session {
+    pub fn insert(conn: &DbConnection, session: Session) -> QueryResult<()> {
+        with_connection!(conn, conn => diesel::insert_into(sessions_dsl)
+            .values(&session)
+            .execute(conn))?;
+        Ok(())
+    }
+    pub fn by_token_hash(conn: &DbConnection, token_hash: String) -> QueryResult<Option<Self>> {
+        let dsl_filter = sessions_dsl.filter(schema::sessions::token_hash.eq(token_hash));
+        with_connection!(conn, conn => dsl_filter.first::<Session>(conn).optional())
+    }
+    pub fn get_by_login(
+        conn: &DbConnection,
+        login: String,
+    ) -> Result<Vec<Session>, diesel::result::Error> {
+        let dsl_filter = sessions_dsl.filter(schema::sessions::login.eq(login));
+        with_connection!(conn, conn => dsl_filter.load::<Session>(conn))
+    }
+    pub fn touch(conn: &DbConnection, id: String, last_seen: NaiveDateTime) -> QueryResult<()> {
+        with_connection!(conn, conn => diesel::update(sessions_dsl.find(id))
+            .set(schema::sessions::last_seen.eq(last_seen))
+            .execute(conn))?;
+        Ok(())
+    }
+    pub fn delete(conn: &DbConnection, id: String, login: String) -> QueryResult<bool> {
+        let dsl_filter = sessions_dsl
+            .filter(schema::sessions::id.eq(id))
+            .filter(schema::sessions::login.eq(login));
+        Ok(with_connection!(conn, conn => diesel::delete(dsl_filter).execute(conn))? != 0)
+    }
+    pub fn delete_by_login(conn: &DbConnection, login: String) -> QueryResult<usize> {
+        let dsl_filter = sessions_dsl.filter(schema::sessions::login.eq(login));
+        with_connection!(conn, conn => diesel::delete(dsl_filter).execute(conn))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Queryable, Insertable, Clone)]
@@ -60,21 +124,116 @@ impl User {
 pub struct History {
     pub id: String,
     pub login: String,
-    pub request: String,
     pub tms: NaiveDateTime,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<String>,
+    pub outcome: String,
+    /// Missing on entries recorded before statuses were.
+    pub status_code: Option<i32>,
+    pub latency_ms: i64,
+    pub client_ip: Option<String>,
+    pub request_id: Option<String>,
+    /// Redacted JSON of the request body, when payloads are captured.
+    pub payload: Option<String>,
+    pub token_fingerprint: Option<String>,
 }
 
 impl History {
-    pub fn add_element(conn: &SqliteConnection, element: History) {
-        let _res = diesel::insert_into(history_dsl)
+    pub fn add_element(conn: &DbConnection, element: History) -> QueryResult<()> {
+        with_connection!(conn, conn => diesel::insert_into(history_dsl)
             .values(&element)
-            .execute(conn);
+            .execute(conn))?;
+        Ok(())
     }
-    pub fn get_by_login(
-        conn: &SqliteConnection,
-        user_name: String,
+    /// Oldest rows first, all older than `before`.
+    pub fn older_than(
+        conn: &DbConnection,
+        before: NaiveDateTime,
+        limit: i64,
+    ) -> QueryResult<Vec<History>> {
+        use schema::history::{id, tms};
+        let dsl_filter = history_dsl
+            .filter(tms.lt(before))
+            .order((tms.asc(), id.asc()))
+            .limit(limit);
+        with_connection!(conn, conn => dsl_filter.load::<History>(conn))
+    }
+    /// Newest row of `user_name` after its `keep` newest ones.
+    pub fn first_beyond(
+        conn: &DbConnection,
+        user_name: &str,
+        keep: i64,
+    ) -> QueryResult<Option<History>> {
+        use schema::history::{id, login, tms};
+        let dsl_filter = history_dsl
+            .filter(login.eq(user_name.to_string()))
+            .order((tms.desc(), id.desc()))
+            .offset(keep);
+        with_connection!(conn, conn => dsl_filter.first::<History>(conn).optional())
+    }
+    /// Oldest rows of the login of `last` up to and including `last`.
+    pub fn through(conn: &DbConnection, last: &History, limit: i64) -> QueryResult<Vec<History>> {
+        use schema::history::{id, login, tms};
+        let dsl_filter = history_dsl
+            .filter(login.eq(last.login.clone()))
+            .filter(
+                tms.lt(last.tms)
+                    .or(tms.eq(last.tms).and(id.le(last.id.clone()))),
+            )
+            .order((tms.asc(), id.asc()))
+            .limit(limit);
+        with_connection!(conn, conn => dsl_filter.load::<History>(conn))
+    }
+    pub fn logins(conn: &DbConnection) -> QueryResult<Vec<String>> {
+        let dsl_filter = history_dsl.select(schema::history::login).distinct();
+        with_connection!(conn, conn => dsl_filter.load::<String>(conn))
+    }
+    pub fn delete_ids(conn: &DbConnection, ids: Vec<String>) -> QueryResult<usize> {
+        let dsl_filter = history_dsl.filter(schema::history::id.eq_any(ids));
+        with_connection!(conn, conn => diesel::delete(dsl_filter).execute(conn))
+    }
+    /// One page of history matching `query`, continuing after `cursor`.
+    /// Fetches one row more than the page size so callers can tell
+    /// whether another page follows.
+    pub fn query(
+        conn: &DbConnection,
+        query: &HistoryQuery,
+        cursor: Option<HistoryCursor>,
     ) -> Result<Vec<History>, diesel::result::Error> {
-        let dsl_filter = schema::history::dsl::history.filter(schema::history::login.eq(user_name));
-        dsl_filter.load::<History>(conn)
+        use schema::history::{action, id, login, tms};
+        with_connection!(conn, conn => {
+            let mut dsl_filter = history_dsl.into_boxed();
+            if let Some(user_name) = &query.login {
+                dsl_filter = dsl_filter.filter(login.eq(user_name.clone()));
+            }
+            if let Some(from) = query.from {
+                dsl_filter = dsl_filter.filter(tms.ge(from));
+            }
+            if let Some(to) = query.to {
+                dsl_filter = dsl_filter.filter(tms.lt(to));
+            }
+            if let Some(name) = &query.action {
+                dsl_filter = dsl_filter.filter(action.eq(name.clone()));
+            }
+            dsl_filter = match (query.order(), cursor.clone()) {
+                (SortOrder::Asc, Some(cursor)) => dsl_filter.filter(
+                    tms.gt(cursor.time)
+                        .or(tms.eq(cursor.time).and(id.gt(cursor.id))),
+                ),
+                (SortOrder::Desc, Some(cursor)) => dsl_filter.filter(
+                    tms.lt(cursor.time)
+                        .or(tms.eq(cursor.time).and(id.lt(cursor.id))),
+                ),
+                (_, None) => dsl_filter,
+            };
+            dsl_filter = match query.order() {
+                SortOrder::Asc => dsl_filter.order((tms.asc(), id.asc())),
+                SortOrder::Desc => dsl_filter.order((tms.desc(), id.desc())),
+            };
+            dsl_filter
+                .limit(query.page_size() + 1)
+                .load::<History>(conn)
+        })
     }
 }
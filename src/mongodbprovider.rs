@@ -3,22 +3,56 @@ use std::{
     fmt,
 };
 
-use crate::{loginmanager::LogMngTrait, mydatastruct::MyData};
+use crate::{
+    audit::{self, AuditContext, AuditEvent},
+    dataerror::DataError,
+    loginmanager::LogMngTrait,
+    metrics,
+    mydatastruct::{DataPage, DataQuery, MyData, SortField},
+    permissions::{Credential, Permission},
+    problem,
+};
 use async_trait::async_trait;
 use futures_util::stream::StreamExt;
-use mongodb::{bson::doc, options::ClientOptions, Client, Database};
+use mongodb::{
+    bson::{doc, to_bson, to_document, Document},
+    options::{ClientOptions, FindOptions},
+    Client, Database,
+};
+use serde_json::Value;
 
 use tracing::{debug, info, log::warn};
 
-use warp::{
-    http,
-    reply::{self, Json},
-};
+use warp::{http, reply};
 
 #[async_trait]
 pub trait MongoDBProviderTrait: Send {
-    async fn insert_struct_to_db(&self, data: MyData) -> Result<(), String>;
-    async fn read_from(&self, id: String) -> Result<Vec<MyData>, String>;
+    async fn insert_struct_to_db(&self, data: MyData) -> Result<(), DataError>;
+    async fn read_from(&self, id: String) -> Result<Vec<MyData>, DataError>;
+    async fn replace_struct(&self, id: String, data: MyData) -> Result<(), DataError>;
+    /// Replaces `current` with `data` only if the stored record still equals
+    /// `current`; fails with `Conflict` if it changed and `NotFound` if it
+    /// is gone.
+    async fn replace_if_unchanged(&self, current: MyData, data: MyData) -> Result<(), DataError>;
+    async fn delete_struct(&self, id: String) -> Result<(), DataError>;
+    async fn list_structs(&self, query: DataQuery) -> Result<DataPage, DataError>;
+    /// Round trip to the server; the driver only connects on first use.
+    async fn ping(&self) -> Result<(), DataError>;
+
+    /// JSON Merge Patch on top of `read_from` and `replace_if_unchanged`,
+    /// so a concurrent write makes it fail instead of being lost.
+    async fn patch_struct(&self, id: String, patch: Value) -> Result<MyData, DataError>
+    where
+        Self: Sync,
+    {
+        let current = match self.read_from(id.clone()).await?.into_iter().next() {
+            Some(current) => current,
+            None => return Err(DataError::NotFound),
+        };
+        let patched = current.merge_patch(&patch)?;
+        self.replace_if_unchanged(current, patched.clone()).await?;
+        Ok(patched)
+    }
 }
 #[derive(Clone)]
 pub struct MongoConnectionParameters {
@@ -26,8 +60,13 @@ pub struct MongoConnectionParameters {
     pub port: i32,
     pub user_name: String,
     pub password: String,
+    pub database: String,
+    pub collection: String,
 }
 
+pub const DEFAULT_DATABASE: &str = "mydata";
+pub const DEFAULT_COLLECTION: &str = "dobro";
+
 impl fmt::Display for MongoConnectionParameters {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.user_name.is_empty() {
@@ -44,8 +83,9 @@ impl fmt::Display for MongoConnectionParameters {
 
 #[derive(Clone)]
 pub struct MongoDBProvider {
-    _client: Client,
+    client: Client,
     database: Database,
+    collection: String,
 }
 impl MongoDBProvider {
     pub async fn new(params: MongoConnectionParameters) -> MongoDBProvider {
@@ -53,101 +93,405 @@ impl MongoDBProvider {
         //  .await
         //.unwrap();
         let client_options = ClientOptions::parse(params.to_string()).await.unwrap();
-        info!("Creating Mongo client with options: {:#?}", client_options);
+        info!(
+            "Creating Mongo client for {:?}, database {}",
+            client_options.hosts, params.database
+        );
         let client = Client::with_options(client_options).unwrap();
-        let database = client.database("mydata");
+        let database = client.database(&params.database);
         MongoDBProvider {
-            _client: client,
+            client,
             database,
+            collection: params.collection,
         }
     }
+
+    /// Closes the connection pool; later operations on clones fail.
+    pub async fn shutdown(self) {
+        self.client.shutdown().await;
+        info!("Mongo client closed");
+    }
 }
 #[async_trait]
 impl MongoDBProviderTrait for MongoDBProvider {
-    async fn insert_struct_to_db(&self, data: MyData) -> Result<(), String> {
-        let collection = self.database.collection::<MyData>("dobro");
-        info!("Inserting struct to DB: {:#?}", data);
-        match collection.insert_one(data, None).await {
-            Ok(result) => {
-                info!("Successful insertion with id {}", result.inserted_id);
-                return futures_util::__private::Ok(());
+    async fn insert_struct_to_db(&self, data: MyData) -> Result<(), DataError> {
+        metrics::time_mongo("insert", async {
+            let collection = self.database.collection::<MyData>(&self.collection);
+            info!("Inserting struct to DB: {:#?}", data);
+            let id = data.id_getter();
+            match collection.insert_one(data, None).await {
+                Ok(result) => {
+                    info!("Successful insertion with id {}", result.inserted_id);
+                    Ok(())
+                }
+                Err(err) => {
+                    warn!("Insertion failed due to {}", err);
+                    Err(match DataError::from(err) {
+                        DataError::Duplicate(_) => DataError::Duplicate(id),
+                        other => other,
+                    })
+                }
             }
-            Err(err) => {
-                warn!("Insertion failed due to {}", err);
-                return futures_util::__private::Err(err.to_string());
+        })
+        .await
+    }
+    async fn read_from(&self, id: String) -> Result<Vec<MyData>, DataError> {
+        metrics::time_mongo("find", async {
+            let collection = self.database.collection::<MyData>(&self.collection);
+            info!("Searching for id {}", id);
+            let search_result = collection.find(doc! {"_id":id}, None).await;
+            match search_result {
+                Ok(mut cursor) => {
+                    let mut vec_res: Vec<MyData> = Vec::new();
+                    while let Some(dt) = cursor.next().await {
+                        match dt {
+                            Ok(elem) => vec_res.push(elem),
+                            Err(err) => {
+                                warn!("Internal search error {}", err);
+                                return Err(err.into());
+                            }
+                        }
+                    }
+                    if !vec_res.is_empty() {
+                        info!("Got {} result", vec_res.len());
+                        Ok(vec_res)
+                    } else {
+                        warn!("Not Found!");
+                        Err(DataError::NotFound)
+                    }
+                }
+                Err(err) => {
+                    warn!("Search failed due to {}", err);
+                    Err(err.into())
+                }
             }
-        }
+        })
+        .await
+    }
+    async fn replace_struct(&self, id: String, data: MyData) -> Result<(), DataError> {
+        metrics::time_mongo("replace", async {
+            let collection = self.database.collection::<MyData>(&self.collection);
+            info!("Replacing struct with id {}: {:#?}", id, data);
+            match collection.replace_one(doc! {"_id":id}, data, None).await {
+                Ok(result) if result.matched_count == 0 => {
+                    warn!("Not Found!");
+                    Err(DataError::NotFound)
+                }
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    warn!("Replacement failed due to {}", err);
+                    Err(err.into())
+                }
+            }
+        })
+        .await
     }
-    async fn read_from(&self, id: String) -> Result<Vec<MyData>, String> {
-        let collection = self.database.collection::<MyData>("dobro");
-        info!("Searching for id {}", id);
-        let search_result = collection.find(doc! {"_id":id}, None).await;
-        if let Ok(mut cursor) = search_result {
+    async fn replace_if_unchanged(&self, current: MyData, data: MyData) -> Result<(), DataError> {
+        metrics::time_mongo("replace", async {
+            let collection = self.database.collection::<MyData>(&self.collection);
+            let id = current.id_getter();
+            let filter =
+                to_document(&current).map_err(|err| DataError::Internal(err.to_string()))?;
+            info!("Replacing struct with id {} if unchanged", id);
+            match collection.replace_one(filter, data, None).await {
+                Ok(result) if result.matched_count == 0 => {
+                    let exists = collection.count_documents(doc! {"_id": &id}, None).await? != 0;
+                    if exists {
+                        warn!("Struct with id {} changed concurrently", id);
+                        Err(DataError::Conflict(id))
+                    } else {
+                        warn!("Not Found!");
+                        Err(DataError::NotFound)
+                    }
+                }
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    warn!("Replacement failed due to {}", err);
+                    Err(err.into())
+                }
+            }
+        })
+        .await
+    }
+    async fn delete_struct(&self, id: String) -> Result<(), DataError> {
+        metrics::time_mongo("delete", async {
+            let collection = self.database.collection::<MyData>(&self.collection);
+            info!("Deleting struct with id {}", id);
+            match collection.delete_one(doc! {"_id":id}, None).await {
+                Ok(result) if result.deleted_count == 0 => {
+                    warn!("Not Found!");
+                    Err(DataError::NotFound)
+                }
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    warn!("Deletion failed due to {}", err);
+                    Err(err.into())
+                }
+            }
+        })
+        .await
+    }
+    async fn list_structs(&self, query: DataQuery) -> Result<DataPage, DataError> {
+        metrics::time_mongo("list", async {
+            let collection = self.database.collection::<MyData>(&self.collection);
+            info!("Listing structs with {:?}", query);
+            let options = FindOptions::builder()
+                .sort(match query.sort_field() {
+                    SortField::Id => doc! {"_id": 1},
+                    SortField::Age => doc! {"age": 1, "_id": 1},
+                })
+                .limit(query.page_size() + 1)
+                .build();
+            let mut cursor = match collection.find(list_filter(&query)?, options).await {
+                Ok(cursor) => cursor,
+                Err(err) => {
+                    warn!("Listing failed due to {}", err);
+                    return Err(err.into());
+                }
+            };
             let mut vec_res: Vec<MyData> = Vec::new();
             while let Some(dt) = cursor.next().await {
-                if let Ok(elem) = dt {
-                    vec_res.push(elem);
-                } else {
-                    warn!("Internal search error!");
-                    return Err("Internal Error".to_string());
+                match dt {
+                    Ok(elem) => vec_res.push(elem),
+                    Err(err) => {
+                        warn!("Internal search error {}", err);
+                        return Err(err.into());
+                    }
                 }
             }
-            if !vec_res.is_empty() {
-                info!("Got {} result", vec_res.len());
-                return Ok(vec_res);
-            } else {
-                warn!("Not Found!");
-                return Err("Not Found!".to_string());
+            Ok(query.make_page(vec_res))
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<(), DataError> {
+        metrics::time_mongo("ping", async {
+            self.database
+                .run_command(doc! {"ping": 1}, None)
+                .await
+                .map_err(|err| {
+                    warn!("Ping failed due to {}", err);
+                    DataError::from(err)
+                })?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn list_filter(query: &DataQuery) -> Result<Document, DataError> {
+    let mut conditions = Vec::new();
+    if let Some(first_name) = &query.first_name {
+        conditions.push(doc! {"first_name": first_name});
+    }
+    if let Some(sex) = &query.sex {
+        conditions
+            .push(doc! {"sex": to_bson(sex).map_err(|err| DataError::Internal(err.to_string()))?});
+    }
+    if let Some(min_age) = query.min_age {
+        conditions.push(doc! {"age": {"$gte": min_age}});
+    }
+    if let Some(max_age) = query.max_age {
+        conditions.push(doc! {"age": {"$lte": max_age}});
+    }
+    if let Some(cursor) = query.decoded_cursor()? {
+        conditions.push(match query.sort_field() {
+            SortField::Id => doc! {"_id": {"$gt": cursor.id}},
+            SortField::Age => {
+                let age = cursor.age.unwrap_or_default();
+                doc! {"$or": [
+                    {"age": {"$gt": age}},
+                    {"age": age, "_id": {"$gt": cursor.id}},
+                ]}
             }
-        } else {
-            warn!("Internal search error!");
-            return Err("Search Error".to_string());
-        }
+        });
+    }
+    if conditions.is_empty() {
+        Ok(doc! {})
+    } else {
+        Ok(doc! {"$and": conditions})
     }
 }
 pub async fn add_to_db(
     db: impl MongoDBProviderTrait + Clone + Sync,
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
     data: MyData,
-    token: String,
+    credential: Credential,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     debug!("insert route");
-    if !mngr.check_token(token, format!("Add data to DB {:?}", data.clone())) {
-        return Ok(create_forb_rep());
-    }
-    match db.insert_struct_to_db(data).await {
-        Ok(_) => Ok(reply::with_status(
-            reply::json(&"Item successfully created".to_string()),
-            http::StatusCode::CREATED,
-        )),
-        Err(err_str) => Ok(reply::with_status(
-            reply::json(&err_str),
-            warp::http::StatusCode::NOT_ACCEPTABLE,
-        )),
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::CreateData(data.clone());
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::WriteData, None)
+            .map_err(problem::reject)?;
+        match db.insert_struct_to_db(data).await {
+            Ok(_) => Ok(reply::with_status(
+                reply::json(&"Item successfully created".to_string()),
+                http::StatusCode::CREATED,
+            )),
+            Err(err) => Err(problem::reject(err)),
+        }
     }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
 }
 
 pub async fn get_by_id(
     db: impl MongoDBProviderTrait,
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
     id: String,
-    token: String,
+    credential: Credential,
+    context: AuditContext,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     debug!("get route");
-    if !mngr.check_token(token, format!("Get data from DB by id {}", id.clone())) {
-        return Ok(create_forb_rep());
-    }
-    match db.read_from(id).await {
-        Ok(res) => Ok(reply::with_status(
-            reply::json(&res),
-            http::StatusCode::FOUND,
-        )),
-        Err(err_str) => Ok(reply::with_status(
-            reply::json(&err_str),
-            http::StatusCode::NOT_FOUND,
-        )),
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::GetData { id: id.clone() };
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::ReadData, None)
+            .map_err(problem::reject)?;
+        match db.read_from(id).await {
+            Ok(res) => Ok(reply::with_status(
+                reply::json(&res),
+                http::StatusCode::FOUND,
+            )),
+            Err(err) => Err(problem::reject(err)),
+        }
+    }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
+}
+pub async fn list_from_db(
+    db: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+    query: DataQuery,
+    credential: Credential,
+    context: AuditContext,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("list route");
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::ListData(query.clone());
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::ReadData, None)
+            .map_err(problem::reject)?;
+        match db.list_structs(query).await {
+            Ok(page) => Ok(reply::with_status(reply::json(&page), http::StatusCode::OK)),
+            Err(err) => Err(problem::reject(err)),
+        }
     }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
 }
+
+pub async fn replace_in_db(
+    db: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+    id: String,
+    data: MyData,
+    credential: Credential,
+    context: AuditContext,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("replace route");
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::ReplaceData {
+        id: id.clone(),
+        data: data.clone(),
+    };
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::WriteData, None)
+            .map_err(problem::reject)?;
+        if data.id_getter() != id {
+            return Err(problem::reject(DataError::Validation(
+                "id mismatch!".to_string(),
+            )));
+        }
+        match db.replace_struct(id, data).await {
+            Ok(_) => Ok(reply::with_status(
+                reply::json(&"Item successfully updated".to_string()),
+                http::StatusCode::OK,
+            )),
+            Err(err) => Err(problem::reject(err)),
+        }
+    }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
+}
+
+pub async fn patch_in_db(
+    db: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+    id: String,
+    patch: Value,
+    credential: Credential,
+    context: AuditContext,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("patch route");
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::PatchData {
+        id: id.clone(),
+        patch: patch.clone(),
+    };
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::WriteData, None)
+            .map_err(problem::reject)?;
+        match db.patch_struct(id, patch).await {
+            Ok(res) => Ok(reply::with_status(reply::json(&res), http::StatusCode::OK)),
+            Err(err) => Err(problem::reject(err)),
+        }
+    }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
+}
+
+pub async fn delete_from_db(
+    db: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+    id: String,
+    credential: Credential,
+    context: AuditContext,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    debug!("delete route");
+    let identity = mngr.authenticate(credential.clone()).await;
+    let event = AuditEvent::DeleteData { id: id.clone() };
+    let result = async {
+        let identity = identity
+            .as_ref()
+            .map_err(|err| problem::reject(err.clone()))?;
+        identity
+            .check(Permission::WriteData, None)
+            .map_err(problem::reject)?;
+        match db.delete_struct(id).await {
+            Ok(_) => Ok(reply::with_status(
+                reply::json(&"Item successfully deleted".to_string()),
+                http::StatusCode::NO_CONTENT,
+            )),
+            Err(err) => Err(problem::reject(err)),
+        }
+    }
+    .await;
+    audit::record(&mngr, context, &credential, &identity, event, result).await
+}
+
 pub fn get_db_address_from_env() -> Result<String, VarError> {
     match env::var("TEST_MONGO_ADDRESS") {
         Ok(val) => {
@@ -160,9 +504,3 @@ pub fn get_db_address_from_env() -> Result<String, VarError> {
         }
     }
 }
-fn create_forb_rep() -> reply::WithStatus<Json> {
-    reply::with_status(
-        reply::json(&"Wrong token".to_string()),
-        http::StatusCode::FORBIDDEN,
-    )
-}
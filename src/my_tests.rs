@@ -1,15 +1,23 @@
 #[cfg(all(test, feature = "integration_tests"))]
 mod tests {
+    use crate::dataerror::DataError;
+    use crate::health::{Readiness, Status};
     use crate::loginmanager::{LogMngTrait, SimplifiedUser};
     use crate::mongodbprovider::{self, MongoDBProvider, MongoDBProviderTrait};
     use crate::mydatastruct;
-    use crate::mydatastruct::MyData;
-    use crate::routes::{get_filter_fcn, insert_filter_fcn};
+    use crate::mydatastruct::{DataPage, DataQuery, MyData, SortField};
+    use crate::permissions::Role;
+    use crate::problem;
+    use crate::ratelimit::{Limits, RateLimiter};
+    use crate::routes::{
+        api, delete_filter_fcn, get_filter_fcn, insert_filter_fcn, list_filter_fcn,
+        patch_filter_fcn, put_filter_fcn,
+    };
     use crate::testlogin::MockLogMngr;
     use async_trait::async_trait;
     use serde_json::json;
     use std::collections::BTreeMap;
-    use std::sync::{Arc, RwLock};
+    use std::sync::Arc;
     use testcontainers::clients::Cli;
     use testcontainers::images::generic::{GenericImage, WaitFor};
     use testcontainers::{clients, Container, Docker, RunArgs};
@@ -24,27 +32,98 @@ mod tests {
 
     #[async_trait]
     impl MongoDBProviderTrait for FakeMongoProvider2 {
-        async fn insert_struct_to_db(&self, data: MyData) -> Result<(), String> {
+        async fn insert_struct_to_db(&self, data: MyData) -> Result<(), DataError> {
             let mut inner = self.inner.write().await;
+            if inner.contains_key(&data.id_getter()) {
+                return Err(DataError::Duplicate(data.id_getter()));
+            }
             inner.insert(data.id_getter(), data);
             Ok(())
         }
 
-        async fn read_from(&self, id: String) -> Result<Vec<MyData>, String> {
+        async fn read_from(&self, id: String) -> Result<Vec<MyData>, DataError> {
             let inner = self.inner.write().await;
             match inner.get(&id) {
-                None => Err("err".to_string()),
+                None => Err(DataError::NotFound),
                 Some(data) => Ok(vec![data.clone()]),
             }
         }
+
+        async fn replace_struct(&self, id: String, data: MyData) -> Result<(), DataError> {
+            let mut inner = self.inner.write().await;
+            match inner.get_mut(&id) {
+                None => Err(DataError::NotFound),
+                Some(current) => {
+                    *current = data;
+                    Ok(())
+                }
+            }
+        }
+
+        async fn replace_if_unchanged(
+            &self,
+            current: MyData,
+            data: MyData,
+        ) -> Result<(), DataError> {
+            let mut inner = self.inner.write().await;
+            match inner.get_mut(&current.id_getter()) {
+                None => Err(DataError::NotFound),
+                Some(stored) if *stored != current => Err(DataError::Conflict(current.id_getter())),
+                Some(stored) => {
+                    *stored = data;
+                    Ok(())
+                }
+            }
+        }
+
+        async fn delete_struct(&self, id: String) -> Result<(), DataError> {
+            let mut inner = self.inner.write().await;
+            match inner.remove(&id) {
+                None => Err(DataError::NotFound),
+                Some(_) => Ok(()),
+            }
+        }
+
+        async fn list_structs(&self, query: DataQuery) -> Result<DataPage, DataError> {
+            let cursor = query.decoded_cursor()?;
+            let after = cursor.map(|x| (x.age, x.id));
+            let inner = self.inner.read().await;
+            let mut items: Vec<(Option<i32>, String, MyData)> = inner
+                .values()
+                .filter_map(|data| {
+                    let fields = serde_json::to_value(data).unwrap();
+                    let age = fields["age"].as_i64().unwrap() as i32;
+                    let matches = query.first_name.iter().all(|x| fields["first_name"] == *x)
+                        && query
+                            .sex
+                            .iter()
+                            .all(|x| fields["sex"] == serde_json::to_value(x).unwrap())
+                        && query.min_age.iter().all(|x| age >= *x)
+                        && query.max_age.iter().all(|x| age <= *x);
+                    let key = match query.sort_field() {
+                        SortField::Age => Some(age),
+                        SortField::Id => None,
+                    };
+                    matches.then(|| (key, data.id_getter(), data.clone()))
+                })
+                .filter(|(age, id, _)| after.iter().all(|after| (age, id) > (&after.0, &after.1)))
+                .collect();
+            items.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+            items.truncate(query.page_size() as usize + 1);
+            Ok(query.make_page(items.into_iter().map(|x| x.2).collect()))
+        }
+
+        async fn ping(&self) -> Result<(), DataError> {
+            Ok(())
+        }
     }
 
-    pub fn mongo_setup(docker: &Cli, port: u16) -> Container<Cli, GenericImage> {
+    pub fn mongo_setup(docker: &Cli, port: u16) -> Container<'_, Cli, GenericImage> {
         let mut container_name = "mongo_test".to_string();
         container_name.push_str(port.to_string().as_str());
         let run_args = RunArgs::default()
             .with_name(container_name)
-            .with_mapped_port((port as u16, 27017));
+            .with_mapped_port((port, 27017));
         let generic_mongodb_image = GenericImage::new("mongo:5.0")
             .with_wait_for(WaitFor::message_on_stdout("LogicalSessionCacheRefresh"));
 
@@ -63,6 +142,8 @@ mod tests {
             port: 27018,
             user_name: db_name,
             password: db_pass,
+            database: mongodbprovider::DEFAULT_DATABASE.to_string(),
+            collection: mongodbprovider::DEFAULT_COLLECTION.to_string(),
         })
         .await;
 
@@ -95,6 +176,8 @@ mod tests {
             port: 27019,
             user_name: db_name,
             password: db_pass,
+            database: mongodbprovider::DEFAULT_DATABASE.to_string(),
+            collection: mongodbprovider::DEFAULT_COLLECTION.to_string(),
         })
         .await;
 
@@ -118,17 +201,23 @@ mod tests {
     #[tokio::test]
     async fn rest_post_insert_data_test() {
         let db_provider = FakeMongoProvider2::default();
-        let mngr = MockLogMngr {
-            inner: Arc::new(RwLock::new(BTreeMap::new())),
-        };
+        let mngr = MockLogMngr::default();
         let test_stuct = SimplifiedUser {
             login: "123".to_string(),
             password: "321".to_string(),
+            role: Some(Role::Writer),
         };
-        mngr.insert_new_user(test_stuct.clone());
+        mngr.insert_new_user(test_stuct.clone()).await.unwrap();
+        let token = mngr
+            .create_session(test_stuct.login.clone(), None)
+            .await
+            .unwrap()
+            .token;
         let insert_route = insert_filter_fcn(db_provider.clone(), mngr.clone()).await;
         let data_path = warp::path("data");
-        let data_path_routes = data_path.and(insert_route);
+        let data_path_routes = data_path
+            .and(insert_route)
+            .recover(problem::handle_rejection);
 
         let test_body_request = json!(
             {
@@ -142,12 +231,21 @@ mod tests {
         let req_test = warp::test::request()
             .path("/data")
             .method("POST")
-            .header("autorization", "123")
+            .header("autorization", &token)
             .body(serde_json::to_string(&test_body_request).unwrap())
             .reply(&data_path_routes.clone())
             .await;
         assert_eq!(req_test.status(), StatusCode::CREATED);
 
+        let req_test = warp::test::request()
+            .path("/data")
+            .method("POST")
+            .header("autorization", &token)
+            .body(serde_json::to_string(&test_body_request).unwrap())
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::CONFLICT);
+
         let inserted_data = db_provider.read_from("test".to_string()).await.unwrap();
         assert_eq!(
             inserted_data[0],
@@ -172,22 +270,28 @@ mod tests {
         );
 
         db_provider.insert_struct_to_db(test_struct).await.unwrap();
-        let mngr = MockLogMngr {
-            inner: Arc::new(RwLock::new(BTreeMap::new())),
-        };
+        let mngr = MockLogMngr::default();
         let test_stuct = SimplifiedUser {
             login: "123".to_string(),
             password: "321".to_string(),
+            role: Some(Role::Writer),
         };
-        mngr.insert_new_user(test_stuct.clone());
+        mngr.insert_new_user(test_stuct.clone()).await.unwrap();
+        let token = mngr
+            .create_session(test_stuct.login.clone(), None)
+            .await
+            .unwrap()
+            .token;
         let insert_route = get_filter_fcn(db_provider, mngr.clone()).await;
         let data_path = warp::path("data");
-        let data_path_routes = data_path.and(insert_route);
+        let data_path_routes = data_path
+            .and(insert_route)
+            .recover(problem::handle_rejection);
 
         let req_test = warp::test::request()
             .path("/data/test")
             .method("GET")
-            .header("autorization", "123")
+            .header("autorization", &token)
             .reply(&data_path_routes.clone())
             .await;
         assert_eq!(req_test.status(), StatusCode::FOUND);
@@ -208,5 +312,453 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn rest_get_read_data_without_data_contains_test() {}
+    async fn rest_get_read_data_without_data_contains_test() {
+        let db_provider = FakeMongoProvider2::default();
+        let mngr = MockLogMngr::default();
+        let token = mngr.with_user("123", "321", Role::Reader).await;
+        let data_path = warp::path("data");
+        let data_path_routes = data_path
+            .and(get_filter_fcn(db_provider, mngr.clone()).await)
+            .recover(problem::handle_rejection);
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rest_put_patch_delete_data_test() {
+        let db_provider = FakeMongoProvider2::default();
+        db_provider
+            .insert_struct_to_db(mydatastruct::create_my_struct(
+                "test".to_string(),
+                "AAA".to_string(),
+                53,
+                mydatastruct::Sex::Female,
+            ))
+            .await
+            .unwrap();
+        let mngr = MockLogMngr::default();
+        let token = mngr.with_user("123", "321", Role::Writer).await;
+        let data_path = warp::path("data");
+        let data_path_routes = data_path
+            .and(put_filter_fcn(db_provider.clone(), mngr.clone()).await)
+            .or(data_path.and(patch_filter_fcn(db_provider.clone(), mngr.clone()).await))
+            .or(data_path.and(delete_filter_fcn(db_provider.clone(), mngr.clone()).await))
+            .recover(problem::handle_rejection);
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("PUT")
+            .header("autorization", &token)
+            .json(&json!({"_id": "other", "first_name": "BBB", "age": 20, "sex": "Male"}))
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::BAD_REQUEST);
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("PUT")
+            .header("autorization", &token)
+            .json(&json!({"_id": "test", "first_name": "BBB", "age": 20, "sex": "Male"}))
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("PATCH")
+            .header("autorization", &token)
+            .json(&json!({"age": 21}))
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        assert_eq!(
+            db_provider.read_from("test".to_string()).await.unwrap()[0],
+            mydatastruct::create_my_struct(
+                "test".to_string(),
+                "BBB".to_string(),
+                21,
+                mydatastruct::Sex::Male
+            )
+        );
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("PATCH")
+            .header("autorization", &token)
+            .json(&json!({"first_name": null}))
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::BAD_REQUEST);
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("DELETE")
+            .header("autorization", &token)
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::NO_CONTENT);
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("DELETE")
+            .header("autorization", &token)
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn merge_patch_test() {
+        let data = mydatastruct::create_my_struct(
+            "test".to_string(),
+            "AAA".to_string(),
+            53,
+            mydatastruct::Sex::Female,
+        );
+        let patched = data
+            .merge_patch(&json!({"first_name": "BBB", "unknown": {"a": 1}}))
+            .unwrap();
+        assert_eq!(
+            patched,
+            mydatastruct::create_my_struct(
+                "test".to_string(),
+                "BBB".to_string(),
+                53,
+                mydatastruct::Sex::Female
+            )
+        );
+        assert!(data.merge_patch(&json!({"_id": "other"})).is_err());
+        assert!(data.merge_patch(&json!({"age": "old"})).is_err());
+        assert!(data.merge_patch(&json!({"sex": null})).is_err());
+    }
+
+    #[tokio::test]
+    async fn merge_patch_route_test() {
+        let db_provider = FakeMongoProvider2::default();
+        db_provider
+            .insert_struct_to_db(mydatastruct::create_my_struct(
+                "test".to_string(),
+                "AAA".to_string(),
+                53,
+                mydatastruct::Sex::Female,
+            ))
+            .await
+            .unwrap();
+        let mngr = MockLogMngr::default();
+        let token = mngr.with_user("123", "321", Role::Writer).await;
+        let data_path_routes = warp::path("data")
+            .and(patch_filter_fcn(db_provider.clone(), mngr.clone()).await)
+            .recover(problem::handle_rejection);
+        let patch = |content_type: &'static str, body: &'static str| {
+            warp::test::request()
+                .path("/data/test")
+                .method("PATCH")
+                .header("autorization", &token)
+                .header("content-type", content_type)
+                .body(body)
+                .reply(&data_path_routes)
+        };
+
+        let req_test = patch("application/merge-patch+json", r#"{"age": 54}"#).await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        let req_test = patch("application/json; charset=utf-8", r#"{"age": 55}"#).await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        assert_eq!(
+            db_provider.read_from("test".to_string()).await.unwrap()[0],
+            mydatastruct::create_my_struct(
+                "test".to_string(),
+                "AAA".to_string(),
+                55,
+                mydatastruct::Sex::Female
+            )
+        );
+
+        let req_test = patch("text/plain", r#"{"age": 56}"#).await;
+        assert_eq!(req_test.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(problem.code, "unsupported_media_type");
+        let req_test = patch("application/merge-patch+json", "{").await;
+        assert_eq!(req_test.status(), StatusCode::BAD_REQUEST);
+        let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(problem.code, "invalid_body");
+    }
+
+    #[tokio::test]
+    async fn patch_conflict_test() {
+        let db_provider = FakeMongoProvider2::default();
+        let stale = mydatastruct::create_my_struct(
+            "test".to_string(),
+            "AAA".to_string(),
+            53,
+            mydatastruct::Sex::Female,
+        );
+        db_provider
+            .insert_struct_to_db(stale.clone())
+            .await
+            .unwrap();
+        let patched = db_provider
+            .patch_struct("test".to_string(), json!({"age": 54}))
+            .await
+            .unwrap();
+        let lost_update = stale.merge_patch(&json!({"first_name": "BBB"})).unwrap();
+        let err = db_provider
+            .replace_if_unchanged(stale, lost_update)
+            .await
+            .unwrap_err();
+        assert_eq!(err, DataError::Conflict("test".to_string()));
+        assert_eq!(
+            db_provider.read_from("test".to_string()).await.unwrap(),
+            vec![patched]
+        );
+        let problem = problem::Problem::from(err);
+        assert_eq!(problem.status, StatusCode::CONFLICT.as_u16());
+        assert_eq!(problem.code, "edit_conflict");
+    }
+
+    #[tokio::test]
+    async fn rest_list_data_test() {
+        let db_provider = FakeMongoProvider2::default();
+        for (id, name, age, sex) in [
+            ("a", "AAA", 30, mydatastruct::Sex::Female),
+            ("b", "BBB", 20, mydatastruct::Sex::Male),
+            ("c", "AAA", 20, mydatastruct::Sex::Female),
+            ("d", "DDD", 40, mydatastruct::Sex::Female),
+        ] {
+            db_provider
+                .insert_struct_to_db(mydatastruct::create_my_struct(
+                    id.to_string(),
+                    name.to_string(),
+                    age,
+                    sex,
+                ))
+                .await
+                .unwrap();
+        }
+        let mngr = MockLogMngr::default();
+        let token = mngr.with_user("123", "321", Role::Reader).await;
+        let data_path = warp::path("data");
+        let data_path_routes = data_path
+            .and(list_filter_fcn(db_provider, mngr.clone()).await)
+            .recover(problem::handle_rejection);
+
+        let req_test = warp::test::request()
+            .path("/data?sex=Female&sort=age&limit=2")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(req_test.body()).unwrap();
+        let ids: Vec<&str> = page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["c", "a"]);
+        let cursor = page["next_cursor"].as_str().unwrap();
+
+        let req_test = warp::test::request()
+            .path(&format!(
+                "/data?sex=Female&sort=age&limit=2&cursor={}",
+                cursor
+            ))
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        let page: serde_json::Value = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        assert_eq!(page["items"][0]["_id"], "d");
+        assert!(page["next_cursor"].is_null());
+
+        let req_test = warp::test::request()
+            .path("/data?cursor=garbage")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::BAD_REQUEST);
+
+        let req_test = warp::test::request()
+            .path("/data")
+            .method("GET")
+            .header("autorization", "wrong")
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn data_error_mapping_test() {
+        use mongodb::bson::{doc, from_document};
+        use mongodb::error::{ErrorKind, WriteError, WriteFailure};
+
+        let write_error: WriteError =
+            from_document(doc! {"code": 11000, "errmsg": "E11000 duplicate key"}).unwrap();
+        let err =
+            mongodb::error::Error::from(ErrorKind::Write(WriteFailure::WriteError(write_error)));
+        assert!(matches!(DataError::from(err), DataError::Duplicate(_)));
+
+        let err = mongodb::error::Error::from(ErrorKind::Io(Arc::new(std::io::Error::from(
+            std::io::ErrorKind::ConnectionRefused,
+        ))));
+        let err = DataError::from(err);
+        assert!(matches!(err, DataError::Unavailable(_)));
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(DataError::NotFound.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn api_routes_test() {
+        let db_provider = FakeMongoProvider2::default();
+        db_provider
+            .insert_struct_to_db(mydatastruct::create_my_struct(
+                "test".to_string(),
+                "AAA".to_string(),
+                53,
+                mydatastruct::Sex::Female,
+            ))
+            .await
+            .unwrap();
+        let mngr = MockLogMngr::default();
+        let token = mngr.with_user("admin", "admin", Role::Admin).await;
+        let routes = warp::path("v1")
+            .and(api(db_provider, mngr.clone(), Limits::default()).await)
+            .recover(problem::handle_rejection);
+
+        let req_test = warp::test::request()
+            .path("/v1/users")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&routes)
+            .await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        let users: Vec<serde_json::Value> = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(users.len(), 1);
+
+        let req_test = warp::test::request()
+            .path("/v1/users/admin")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&routes)
+            .await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        let user: serde_json::Value = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(user["login"], "admin");
+
+        let req_test = warp::test::request()
+            .path("/v1/users/nobody")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&routes)
+            .await;
+        assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+        let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(problem.code, "user_not_found");
+
+        let req_test = warp::test::request()
+            .path("/v1/data")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&routes)
+            .await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+
+        let req_test = warp::test::request()
+            .path("/v1/data/test")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&routes)
+            .await;
+        assert_eq!(req_test.status(), StatusCode::FOUND);
+
+        let req_test = warp::test::request()
+            .path("/data/test")
+            .method("GET")
+            .header("autorization", &token)
+            .reply(&routes)
+            .await;
+        assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+        let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(problem.code, "not_found");
+    }
+
+    #[tokio::test]
+    async fn probes_not_rate_limited_test() {
+        let limits = Limits {
+            requests: Arc::new(RateLimiter::new(0.001, 1)),
+            ..Limits::default()
+        };
+        let routes = api(
+            FakeMongoProvider2::default(),
+            MockLogMngr::default(),
+            limits,
+        )
+        .await
+        .recover(problem::handle_rejection);
+        let get = |path: &'static str| {
+            warp::test::request()
+                .path(path)
+                .remote_addr(([10, 0, 0, 1], 40000).into())
+                .reply(&routes)
+        };
+
+        for path in ["/healthz", "/readyz", "/metrics", "/healthz"] {
+            assert_eq!(get(path).await.status(), StatusCode::OK);
+        }
+        assert_eq!(get("/data").await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(get("/data").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(get("/healthz").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn health_routes_test() {
+        let mngr = MockLogMngr::default();
+        let routes = api(
+            FakeMongoProvider2::default(),
+            mngr.clone(),
+            Limits::default(),
+        )
+        .await
+        .recover(problem::handle_rejection);
+
+        let req_test = warp::test::request().path("/healthz").reply(&routes).await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+
+        let req_test = warp::test::request().path("/readyz").reply(&routes).await;
+        assert_eq!(req_test.status(), StatusCode::OK);
+        let readiness: Readiness = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(readiness.status, Status::Up);
+        assert_eq!(readiness.checks["mongo"].status, Status::Up);
+        assert_eq!(readiness.checks["user_store"].status, Status::Up);
+
+        // Nothing listens there, so the ping runs into the check timeout.
+        let unreachable = MongoDBProvider::new(mongodbprovider::MongoConnectionParameters {
+            address: "127.0.0.1".to_string(),
+            port: 1,
+            user_name: String::new(),
+            password: String::new(),
+            database: mongodbprovider::DEFAULT_DATABASE.to_string(),
+            collection: mongodbprovider::DEFAULT_COLLECTION.to_string(),
+        })
+        .await;
+        let routes = api(unreachable, mngr, Limits::default())
+            .await
+            .recover(problem::handle_rejection);
+        let req_test = warp::test::request().path("/readyz").reply(&routes).await;
+        assert_eq!(req_test.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: Readiness = serde_json::from_slice(req_test.body()).unwrap();
+        assert_eq!(readiness.status, Status::Down);
+        assert_eq!(readiness.checks["mongo"].status, Status::Down);
+        assert!(readiness.checks["mongo"].error.is_some());
+        assert_eq!(readiness.checks["user_store"].status, Status::Up);
+    }
 }
@@ -1,4 +1,10 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::dataerror::DataError;
+
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct MyData {
@@ -28,4 +34,132 @@ impl MyData {
     pub fn id_getter(&self) -> String {
         self.id.clone()
     }
+
+    /// Applies an RFC 7396 JSON Merge Patch, keeping the record id fixed.
+    pub fn merge_patch(&self, patch: &Value) -> Result<MyData, DataError> {
+        let mut target =
+            serde_json::to_value(self).map_err(|err| DataError::Internal(err.to_string()))?;
+        merge(&mut target, patch);
+        let patched: MyData = serde_json::from_value(target)
+            .map_err(|err| DataError::Validation(format!("Invalid patch: {}", err)))?;
+        if patched.id != self.id {
+            return Err(DataError::Validation(
+                "Invalid patch: _id can not be changed".to_string(),
+            ));
+        }
+        Ok(patched)
+    }
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    if let Value::Object(patch_map) = patch {
+        if !target.is_object() {
+            *target = Value::Object(serde_json::Map::new());
+        }
+        if let Value::Object(target_map) = target {
+            for (key, value) in patch_map {
+                if value.is_null() {
+                    target_map.remove(key);
+                } else {
+                    merge(target_map.entry(key.as_str()).or_insert(Value::Null), value);
+                }
+            }
+        }
+    } else {
+        *target = patch.clone();
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Id,
+    Age,
+}
+
+/// Filters and paging of `GET /data`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DataQuery {
+    pub first_name: Option<String>,
+    pub sex: Option<Sex>,
+    pub min_age: Option<i32>,
+    pub max_age: Option<i32>,
+    pub sort: Option<SortField>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DataPage {
+    pub items: Vec<MyData>,
+    pub next_cursor: Option<String>,
+}
+
+/// Sort key of the last item of a page; continuation starts right after it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DataCursor {
+    pub id: String,
+    pub age: Option<i32>,
+}
+
+impl DataCursor {
+    pub fn encode(&self) -> String {
+        base64::encode_config(
+            serde_json::to_vec(self).unwrap_or_default(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, DataError> {
+        base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| DataError::Validation("Invalid cursor".to_string()))
+    }
+}
+
+impl DataQuery {
+    pub fn sort_field(&self) -> SortField {
+        self.sort.unwrap_or(SortField::Id)
+    }
+
+    pub fn page_size(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+
+    pub fn decoded_cursor(&self) -> Result<Option<DataCursor>, DataError> {
+        let cursor = match &self.cursor {
+            Some(cursor) => DataCursor::decode(cursor)?,
+            None => return Ok(None),
+        };
+        if self.sort_field() == SortField::Age && cursor.age.is_none() {
+            return Err(DataError::Validation("Invalid cursor".to_string()));
+        }
+        Ok(Some(cursor))
+    }
+
+    pub fn cursor_for(&self, last: &MyData) -> DataCursor {
+        DataCursor {
+            id: last.id.clone(),
+            age: match self.sort_field() {
+                SortField::Age => Some(last.age),
+                SortField::Id => None,
+            },
+        }
+    }
+
+    /// Builds a page out of up to `page_size() + 1` sorted, filtered items,
+    /// the extra one only telling whether there is a next page.
+    pub fn make_page(&self, mut items: Vec<MyData>) -> DataPage {
+        let page_size = self.page_size() as usize;
+        let next_cursor = if items.len() > page_size {
+            items.truncate(page_size);
+            items.last().map(|last| self.cursor_for(last).encode())
+        } else {
+            None
+        };
+        DataPage { items, next_cursor }
+    }
 }
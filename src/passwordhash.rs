@@ -0,0 +1,69 @@
+use std::convert::TryFrom;
+
+use argon2::{
+    password_hash::{self, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use rand::rngs::OsRng;
+
+/// Result of checking a plain password against a stored value.
+#[derive(Debug, PartialEq)]
+pub enum PasswordCheck {
+    Invalid,
+    Valid,
+    /// Password is correct, but the stored value is plaintext or was hashed
+    /// with other parameters and should be replaced with `hash_password`.
+    NeedsRehash,
+}
+
+fn hasher() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+/// Hashes `password` with Argon2id and a random salt, returning a PHC string.
+pub fn hash_password(password: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(hasher()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(password: &str, stored: &str) -> PasswordCheck {
+    let parsed = match PasswordHash::new(stored) {
+        Ok(parsed) => parsed,
+        // Rows written before hashing was introduced hold the password itself.
+        Err(_) => {
+            return if password == stored {
+                PasswordCheck::NeedsRehash
+            } else {
+                PasswordCheck::Invalid
+            }
+        }
+    };
+    if hasher()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return PasswordCheck::Invalid;
+    }
+    if is_current(&parsed) {
+        PasswordCheck::Valid
+    } else {
+        PasswordCheck::NeedsRehash
+    }
+}
+
+fn is_current(parsed: &PasswordHash) -> bool {
+    let current = Params::default();
+    let same_params = match Params::try_from(parsed) {
+        Ok(params) => {
+            params.m_cost() == current.m_cost()
+                && params.t_cost() == current.t_cost()
+                && params.p_cost() == current.p_cost()
+        }
+        Err(_) => false,
+    };
+    parsed.algorithm == Algorithm::Argon2id.ident()
+        && parsed.version == Some(Version::V0x13.into())
+        && same_params
+}
@@ -0,0 +1,109 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::loginerror::LoginError;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Writer,
+    #[default]
+    Reader,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    ReadData,
+    WriteData,
+    ReadUsers,
+    WriteUsers,
+    ReadHistory,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Writer => "writer",
+            Role::Reader => "reader",
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Writer => matches!(permission, Permission::ReadData | Permission::WriteData),
+            Role::Reader => permission == Permission::ReadData,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "writer" => Ok(Role::Writer),
+            "reader" => Ok(Role::Reader),
+            _ => Err(format!("Unknown role {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Permission::ReadData => "read_data",
+            Permission::WriteData => "write_data",
+            Permission::ReadUsers => "read_users",
+            Permission::WriteUsers => "write_users",
+            Permission::ReadHistory => "read_history",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    InvalidToken,
+    Forbidden(Permission),
+    Store(LoginError),
+}
+
+impl From<LoginError> for AuthError {
+    fn from(err: LoginError) -> Self {
+        AuthError::Store(err)
+    }
+}
+
+/// What a caller presented to prove who they are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+    /// Session token from the `autorization` header.
+    Token(String),
+    /// Login named by a client certificate the TLS layer already verified.
+    ClientCert(String),
+}
+
+/// Authenticated caller of a route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub login: String,
+    pub role: Role,
+}
+
+impl Identity {
+    /// Grants `permission` either through the role or, when `owner` is the
+    /// caller's own login, as a self-service operation.
+    pub fn check(&self, permission: Permission, owner: Option<&str>) -> Result<(), AuthError> {
+        if self.role.allows(permission) || owner == Some(self.login.as_str()) {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden(permission))
+        }
+    }
+}
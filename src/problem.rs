@@ -0,0 +1,197 @@
+use std::{convert::Infallible, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use warp::{
+    body::BodyDeserializeError,
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        HeaderValue, StatusCode,
+    },
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, Reject, UnsupportedMediaType,
+    },
+    reply::Response,
+    Rejection, Reply,
+};
+
+use crate::{dataerror::DataError, loginerror::LoginError, permissions::AuthError};
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// RFC 7807 error body shared by every route.
+///
+/// `code` is a stable machine-readable identifier; `title` and `detail`
+/// are meant for humans and may change.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    /// Seconds until a throttled request may be retried, sent as `Retry-After`.
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &str, detail: impl Into<String>) -> Self {
+        Problem {
+            problem_type: "about:blank".to_string(),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: detail.into(),
+            code: code.to_string(),
+            retry_after: None,
+        }
+    }
+
+    /// Rounds `delay` up to whole seconds, so retrying then succeeds.
+    pub fn with_retry_after(mut self, delay: Duration) -> Self {
+        let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+        self.retry_after = Some(secs.max(1));
+        self
+    }
+
+    pub fn internal(detail: impl Into<String>) -> Self {
+        Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", detail)
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+impl Reject for Problem {}
+
+impl Reply for Problem {
+    fn into_response(self) -> Response {
+        let status = self.status_code();
+        let mut res = warp::reply::with_status(warp::reply::json(&self), status).into_response();
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE));
+        if let Some(secs) = self.retry_after {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        res
+    }
+}
+
+impl From<AuthError> for Problem {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::InvalidToken => {
+                Problem::new(StatusCode::FORBIDDEN, "invalid_token", "Wrong token")
+            }
+            AuthError::Forbidden(permission) => Problem::new(
+                StatusCode::FORBIDDEN,
+                "forbidden",
+                format!("Missing permission {}", permission),
+            ),
+            AuthError::Store(err) => err.into(),
+        }
+    }
+}
+
+impl From<LoginError> for Problem {
+    fn from(err: LoginError) -> Self {
+        let code = match &err {
+            LoginError::Unavailable(reason) => {
+                warn!("User store unavailable: {}", reason);
+                "store_unavailable"
+            }
+            LoginError::Invalid(_) => "invalid_query",
+            LoginError::Internal(reason) => {
+                warn!("User store failure: {}", reason);
+                "internal_error"
+            }
+        };
+        Problem::new(err.status_code(), code, err.to_string())
+    }
+}
+
+impl From<DataError> for Problem {
+    fn from(err: DataError) -> Self {
+        let code = match &err {
+            DataError::NotFound => "data_not_found",
+            DataError::Duplicate(_) => "duplicate_id",
+            DataError::Validation(_) => "validation_failed",
+            DataError::Conflict(_) => "edit_conflict",
+            DataError::Unavailable(reason) => {
+                warn!("Storage unavailable: {}", reason);
+                "storage_unavailable"
+            }
+            DataError::Internal(reason) => {
+                warn!("Storage failure: {}", reason);
+                "internal_error"
+            }
+        };
+        Problem::new(err.status_code(), code, err.to_string())
+    }
+}
+
+/// Shorthand for rejecting a request with a domain error.
+pub fn reject(err: impl Into<Problem>) -> Rejection {
+    warp::reject::custom(err.into())
+}
+
+/// Turns any rejection left over by the router into a problem+json reply.
+pub async fn handle_rejection(err: Rejection) -> Result<Problem, Infallible> {
+    let problem = if let Some(problem) = err.find::<Problem>() {
+        problem.clone()
+    } else if let Some(missing) = err.find::<MissingHeader>() {
+        Problem::new(
+            StatusCode::BAD_REQUEST,
+            "missing_header",
+            format!("Missing request header {}", missing.name()),
+        )
+    } else if let Some(invalid) = err.find::<InvalidHeader>() {
+        Problem::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_header",
+            format!("Invalid request header {}", invalid.name()),
+        )
+    } else if let Some(invalid) = err.find::<InvalidQuery>() {
+        Problem::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_query",
+            invalid.to_string(),
+        )
+    } else if let Some(invalid) = err.find::<BodyDeserializeError>() {
+        Problem::new(StatusCode::BAD_REQUEST, "invalid_body", invalid.to_string())
+    } else if err.find::<LengthRequired>().is_some() {
+        Problem::new(
+            StatusCode::LENGTH_REQUIRED,
+            "length_required",
+            "A content-length header is required",
+        )
+    } else if err.find::<PayloadTooLarge>().is_some() {
+        Problem::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "Request body is too large",
+        )
+    } else if err.find::<UnsupportedMediaType>().is_some() {
+        Problem::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Unsupported content type",
+        )
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        Problem::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "HTTP method not allowed",
+        )
+    } else if err.is_not_found() {
+        Problem::new(StatusCode::NOT_FOUND, "not_found", "No such route")
+    } else {
+        warn!("Unhandled rejection {:?}", err);
+        Problem::internal("Unhandled rejection")
+    };
+    Ok(problem)
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+use warp::{http::StatusCode, Filter, Rejection};
+
+use crate::{
+    config::RateLimitConfig,
+    loginmanager::hash_token,
+    problem::{self, Problem},
+    tls::PeerAddr,
+};
+
+/// Above this many tracked keys, idle entries are dropped on the next call.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per key: `burst` requests at once, refilled at
+/// `per_second`. A rate of zero disables the limiter.
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        RateLimiter {
+            burst: f64::from(burst),
+            per_second,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `key`, or returns how long until one is available.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        if self.per_second <= 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // Buckets that refilled completely behave like new ones.
+            buckets.retain(|_, x| {
+                x.tokens + now.duration_since(x.updated).as_secs_f64() * self.per_second
+                    < self.burst
+            });
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Locks a login after repeated failed password checks, doubling the
+/// lock for every further failure. Failures are forgotten after a
+/// successful login or once `max` passed without a new one, and sooner
+/// when so many logins fail that the table has to be pruned.
+pub struct LoginLockout {
+    threshold: u32,
+    base: Duration,
+    max: Duration,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl LoginLockout {
+    pub fn new(threshold: u32, base: Duration, max: Duration) -> Self {
+        LoginLockout {
+            threshold,
+            base,
+            max,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remaining lock of `login`, if any.
+    pub fn check(&self, login: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        match failures.get(login).and_then(|x| x.locked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a failed attempt and returns the lock it triggered, if any.
+    pub fn record_failure(&self, login: &str) -> Option<Duration> {
+        if self.threshold == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= PRUNE_THRESHOLD {
+            self.prune(&mut failures, now);
+        }
+        let entry = failures.entry(login.to_string()).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.duration_since(entry.last) >= self.max {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        if entry.count < self.threshold {
+            return None;
+        }
+        let doublings = (entry.count - self.threshold).min(16);
+        let lock = self.base.saturating_mul(1 << doublings).min(self.max);
+        entry.locked_until = Some(now + lock);
+        Some(lock)
+    }
+
+    pub fn record_success(&self, login: &str) {
+        self.failures.lock().unwrap().remove(login);
+    }
+
+    /// Drops logins that are neither locked nor failed within `base`. If a
+    /// spray still fills the table, the oldest unlocked logins go too, down
+    /// to half of it, so the next pass is thousands of failures away.
+    fn prune(&self, failures: &mut HashMap<String, Failures>, now: Instant) {
+        let locked = |x: &Failures| matches!(x.locked_until, Some(until) if until > now);
+        failures.retain(|_, x| locked(x) || now.duration_since(x.last) < self.base);
+        let excess = failures.len().saturating_sub(PRUNE_THRESHOLD / 2);
+        if excess == 0 {
+            return;
+        }
+        let mut unlocked: Vec<(Instant, String)> = failures
+            .iter()
+            .filter(|(_, x)| !locked(x))
+            .map(|(login, x)| (x.last, login.clone()))
+            .collect();
+        unlocked.sort_unstable();
+        for (_, login) in unlocked.into_iter().take(excess) {
+            failures.remove(&login);
+        }
+    }
+}
+
+/// Request and login limits shared by every route.
+#[derive(Clone)]
+pub struct Limits {
+    pub requests: Arc<RateLimiter>,
+    pub logins: Arc<LoginLockout>,
+}
+
+impl Limits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Limits {
+            requests: Arc::new(RateLimiter::new(config.requests_per_second, config.burst)),
+            logins: Arc::new(LoginLockout::new(
+                config.login_failures_before_lockout,
+                Duration::from_secs(config.lockout_secs),
+                Duration::from_secs(config.max_lockout_secs),
+            )),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::new(&RateLimitConfig::default())
+    }
+}
+
+/// 429 reply telling the client when to retry.
+pub fn too_many_requests(code: &str, detail: impl Into<String>, retry_after: Duration) -> Problem {
+    Problem::new(StatusCode::TOO_MANY_REQUESTS, code, detail).with_retry_after(retry_after)
+}
+
+/// Rejects requests once the client address or the presented token ran
+/// out of tokens.
+pub fn limit(limiter: Arc<RateLimiter>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<PeerAddr>())
+        .and(warp::header::optional::<String>("autorization"))
+        .and_then(
+            move |remote: Option<SocketAddr>, peer: Option<PeerAddr>, token: Option<String>| {
+                let limiter = limiter.clone();
+                async move {
+                    let ip = remote.or(peer.map(|x| x.0)).map(|x| x.ip().to_string());
+                    let keys = ip
+                        .map(|ip| format!("ip:{}", ip))
+                        .into_iter()
+                        .chain(token.map(|token| format!("token:{}", hash_token(&token))));
+                    for key in keys {
+                        if let Err(retry_after) = limiter.check(&key) {
+                            if key.starts_with("ip:") {
+                                warn!("Rate limit hit by {}", key);
+                            } else {
+                                warn!("Rate limit hit by a session token");
+                            }
+                            return Err(problem::reject(too_many_requests(
+                                "rate_limited",
+                                "Too many requests",
+                                retry_after,
+                            )));
+                        }
+                    }
+                    Ok(())
+                }
+            },
+        )
+        .untuple_one()
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use chrono::{NaiveDateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use tokio::{task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::{
+    audit::AuditRecord, config::RetentionConfig, loginerror::LoginError,
+    loginmanager::LoginManager, metrics, models::History,
+};
+
+/// History rows removed by one purge batch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PurgeRule {
+    OlderThan(NaiveDateTime),
+    /// Rows of `login` beyond its `keep` newest ones.
+    Beyond {
+        login: String,
+        keep: i64,
+    },
+}
+
+/// Gzip-compressed NDJSON file receiving the rows of one purge run.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub path: PathBuf,
+}
+
+impl Archive {
+    /// File named after `started` in `dir`.
+    pub fn new(dir: &std::path::Path, started: NaiveDateTime) -> Self {
+        Archive {
+            path: dir.join(format!(
+                "history-{}.ndjson.gz",
+                started.format("%Y%m%dT%H%M%S")
+            )),
+        }
+    }
+
+    /// Writes `rows` as one complete gzip member and syncs the file, so the
+    /// rows are on disk before they are deleted and a run cut short still
+    /// leaves a readable archive.
+    pub fn append(&self, rows: &[History]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        for row in rows {
+            serde_json::to_writer(&mut encoder, &AuditRecord::from(row.clone()))?;
+            encoder.write_all(b"\n")?;
+        }
+        encoder.finish()?.sync_all()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PurgeReport {
+    /// Rows older than `max_age_days`.
+    pub expired: usize,
+    /// Rows beyond `max_rows_per_user`.
+    pub trimmed: usize,
+    /// Archive file, when rows were archived.
+    pub archive: Option<PathBuf>,
+}
+
+/// Applies `config` once, deleting in batches until nothing matches.
+pub async fn purge(
+    mngr: &LoginManager,
+    config: &RetentionConfig,
+) -> Result<PurgeReport, LoginError> {
+    let now = Utc::now().naive_utc();
+    let archive = config
+        .archive_dir
+        .as_ref()
+        .map(|dir| Archive::new(dir, now));
+    let mut report = PurgeReport::default();
+    if let Some(days) = config.max_age_days {
+        let rule = PurgeRule::OlderThan(now - chrono::Duration::days(i64::from(days)));
+        report.expired = purge_rule(mngr, rule, config.batch_size, &archive).await?;
+    }
+    if let Some(keep) = config.max_rows_per_user {
+        for login in mngr.history_logins().await? {
+            let rule = PurgeRule::Beyond {
+                login,
+                keep: i64::from(keep),
+            };
+            report.trimmed += purge_rule(mngr, rule, config.batch_size, &archive).await?;
+        }
+    }
+    if report.expired + report.trimmed > 0 {
+        report.archive = archive.map(|x| x.path);
+    }
+    Ok(report)
+}
+
+async fn purge_rule(
+    mngr: &LoginManager,
+    rule: PurgeRule,
+    batch_size: u32,
+    archive: &Option<Archive>,
+) -> Result<usize, LoginError> {
+    let mut total = 0;
+    loop {
+        let deleted = mngr
+            .purge_history(rule.clone(), i64::from(batch_size), archive.clone())
+            .await?;
+        total += deleted;
+        if deleted < batch_size as usize {
+            return Ok(total);
+        }
+    }
+}
+
+/// Runs `purge` every `interval_secs`, starting right away. Returns `None`
+/// when no retention limit is configured.
+pub fn spawn(mngr: LoginManager, config: RetentionConfig) -> Option<JoinHandle<()>> {
+    if !config.enabled() {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        let mut ticks = time::interval(Duration::from_secs(config.interval_secs));
+        loop {
+            ticks.tick().await;
+            let started = Instant::now();
+            let result = purge(&mngr, &config).await;
+            metrics::record_purge(&result, started.elapsed());
+            match result {
+                Ok(report) => info!(
+                    "Purged {} expired and {} excess history entries in {:?}{}",
+                    report.expired,
+                    report.trimmed,
+                    started.elapsed(),
+                    report
+                        .archive
+                        .map(|x| format!(", archived to {}", x.display()))
+                        .unwrap_or_default()
+                ),
+                Err(err) => warn!("History purge failed: {:?}", err),
+            }
+        }
+    }))
+}
@@ -1,114 +1,378 @@
-use warp::{Filter, Rejection, Reply};
+use std::sync::Arc;
+
+use serde_json::Value;
+use warp::{http::StatusCode, hyper::body::Bytes, Filter, Rejection, Reply};
 
 use crate::{
+    audit::{self, HistoryQuery},
+    health,
     loginmanager::{self, LogMngTrait},
+    metrics,
     mongodbprovider::{self, MongoDBProviderTrait},
+    mydatastruct::DataQuery,
+    permissions::Credential,
+    problem::{self, Problem},
+    ratelimit::{self, Limits, LoginLockout},
+    tls::ClientIdentity,
 };
 
+/// Session token from the `autorization` header or, failing that, the login
+/// of a verified TLS client certificate.
+pub fn credential() -> impl Filter<Extract = (Credential,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("autorization")
+        .and(warp::ext::optional::<ClientIdentity>())
+        .and_then(
+            |token: Option<String>, client: Option<ClientIdentity>| async move {
+                match (token, client) {
+                    (Some(token), _) => Ok(Credential::Token(token)),
+                    (None, Some(client)) => Ok(Credential::ClientCert(client.login)),
+                    (None, None) => Err(problem::reject(Problem::new(
+                        StatusCode::BAD_REQUEST,
+                        "missing_header",
+                        "Missing request header autorization",
+                    ))),
+                }
+            },
+        )
+}
+
+/// JSON Merge Patch body (RFC 7396), sent as `application/merge-patch+json`
+/// or, as before the media type was checked, `application/json`.
+pub fn merge_patch() -> impl Filter<Extract = (Value,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::body::bytes())
+        .and_then(|content_type: Option<String>, body: Bytes| async move {
+            let media_type = content_type
+                .as_deref()
+                .and_then(|x| x.split(';').next())
+                .map(|x| x.trim().to_ascii_lowercase());
+            match media_type.as_deref() {
+                None | Some("application/merge-patch+json") | Some("application/json") => {}
+                Some(_) => {
+                    return Err(problem::reject(Problem::new(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "unsupported_media_type",
+                        "Unsupported content type",
+                    )))
+                }
+            }
+            serde_json::from_slice(&body).map_err(|err| {
+                problem::reject(Problem::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_body",
+                    format!("Request body deserialize error: {}", err),
+                ))
+            })
+        })
+}
+
 pub async fn insert_filter_fcn(
     db_provider: impl MongoDBProviderTrait + Clone + Sync,
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::post()
         .and(warp::path::end())
         .and(warp::any().map(move || db_provider.clone()))
         .and(warp::any().map(move || mngr.clone()))
         .and(warp::body::json())
-        .and(warp::header::<String>("autorization"))
+        .and(credential())
+        .and(audit::context())
         .and_then(mongodbprovider::add_to_db)
 }
 
 pub async fn get_filter_fcn(
     db_provider: impl MongoDBProviderTrait + Clone + Sync,
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::get()
         .and(warp::any().map(move || db_provider.clone()))
         .and(warp::any().map(move || mngr.clone()))
         .and(warp::path::param())
-        .and(warp::header::<String>("autorization"))
+        .and(credential())
         .and(warp::path::end())
+        .and(audit::context())
         .and_then(mongodbprovider::get_by_id)
 }
 
+pub async fn list_filter_fcn(
+    db_provider: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::end())
+        .and(warp::any().map(move || db_provider.clone()))
+        .and(warp::any().map(move || mngr.clone()))
+        .and(warp::query::<DataQuery>())
+        .and(credential())
+        .and(audit::context())
+        .and_then(mongodbprovider::list_from_db)
+}
+
+pub async fn put_filter_fcn(
+    db_provider: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::put()
+        .and(warp::any().map(move || db_provider.clone()))
+        .and(warp::any().map(move || mngr.clone()))
+        .and(warp::path::param())
+        .and(warp::body::json())
+        .and(credential())
+        .and(warp::path::end())
+        .and(audit::context())
+        .and_then(mongodbprovider::replace_in_db)
+}
+
+pub async fn patch_filter_fcn(
+    db_provider: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::patch()
+        .and(warp::any().map(move || db_provider.clone()))
+        .and(warp::any().map(move || mngr.clone()))
+        .and(warp::path::param())
+        .and(merge_patch())
+        .and(credential())
+        .and(warp::path::end())
+        .and(audit::context())
+        .and_then(mongodbprovider::patch_in_db)
+}
+
+pub async fn delete_filter_fcn(
+    db_provider: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::delete()
+        .and(warp::any().map(move || db_provider.clone()))
+        .and(warp::any().map(move || mngr.clone()))
+        .and(warp::path::param())
+        .and(credential())
+        .and(warp::path::end())
+        .and(audit::context())
+        .and_then(mongodbprovider::delete_from_db)
+}
+
 pub async fn login_filter_fcn(
-    login_mgr: impl LogMngTrait + Clone + Sync,
+    login_mgr: impl LogMngTrait + Clone,
+    lockout: Arc<LoginLockout>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("login")
         .and(warp::path::end())
         .and(warp::any().map(move || login_mgr.clone()))
+        .and(warp::any().map(move || lockout.clone()))
         .and(warp::header::<String>("login"))
         .and(warp::header::<String>("password"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(audit::context())
         .and_then(loginmanager::check_login_data)
 }
 
+pub async fn logout_filter_fcn(
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("logout")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::any().map(move || mngr.clone()))
+        .and(warp::header::<String>("autorization"))
+        .and(audit::context())
+        .and_then(loginmanager::logout)
+}
+
+pub async fn get_sessions_fcn(
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("sessions")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map(move || mngr.clone()))
+        .and(warp::header::<String>("autorization"))
+        .and(audit::context())
+        .and_then(loginmanager::get_sessions_list)
+}
+
+pub async fn delete_certain_session(
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("sessions")
+        .and(warp::delete())
+        .and(warp::any().map(move || mngr.clone()))
+        .and(warp::path::param())
+        .and(warp::header::<String>("autorization"))
+        .and(warp::path::end())
+        .and(audit::context())
+        .and_then(loginmanager::delete_certain_session)
+}
+
 pub async fn get_users_fcn(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("users")
         .and(warp::path::end())
         .and(warp::get())
         .and(warp::any().map(move || mngr.clone()))
-        .and(warp::header::<String>("autorization"))
+        .and(credential())
+        .and(audit::context())
         .and_then(loginmanager::get_users_list)
 }
 
 pub async fn post_user_fcn(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("users")
         .and(warp::path::end())
         .and(warp::post())
         .and(warp::any().map(move || mngr.clone()))
         .and(warp::body::json())
-        .and(warp::header::<String>("autorization"))
+        .and(credential())
+        .and(audit::context())
         .and_then(loginmanager::insert_user)
 }
 
 pub async fn get_certain_user(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("users")
         .and(warp::get())
         .and(warp::any().map(move || mngr.clone()))
         .and(warp::path::param())
-        .and(warp::header::<String>("autorization"))
+        .and(credential())
         .and(warp::path::end())
+        .and(audit::context())
         .and_then(loginmanager::get_certain_user)
 }
 
 pub async fn update_certain_user(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("users")
         .and(warp::put())
         .and(warp::any().map(move || mngr.clone()))
         .and(warp::path::param())
         .and(warp::body::json())
-        .and(warp::header::<String>("autorization"))
+        .and(credential())
         .and(warp::path::end())
+        .and(audit::context())
         .and_then(loginmanager::update_certain_user)
 }
 
 pub async fn delete_certain_user(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path("users")
         .and(warp::delete())
         .and(warp::any().map(move || mngr.clone()))
         .and(warp::path::param())
-        .and(warp::header::<String>("autorization"))
+        .and(credential())
         .and(warp::path::end())
+        .and(audit::context())
         .and_then(loginmanager::delete_certain_user)
 }
 
 pub async fn get_history_fcn(
-    mngr: impl LogMngTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // `/history/{login}` is kept as a shorthand for `?login=`.
+    let query = warp::path::end()
+        .and(warp::query::<HistoryQuery>())
+        .or(warp::path::param()
+            .and(warp::path::end())
+            .and(warp::query::<HistoryQuery>())
+            .map(|login: String, query: HistoryQuery| HistoryQuery {
+                login: Some(login),
+                ..query
+            }))
+        .unify();
     warp::path("history")
         .and(warp::get())
         .and(warp::any().map(move || mngr.clone()))
-        .and(warp::path::param())
+        .and(query)
+        .and(credential())
+        .and(audit::context())
+        .and_then(loginmanager::get_history)
+}
+
+pub async fn metrics_fcn() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(metrics::metrics_handler)
+}
+
+pub async fn healthz_fcn() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(health::liveness_handler)
+}
+
+pub async fn readyz_fcn(
+    db_provider: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("readyz")
         .and(warp::path::end())
-        .and_then(loginmanager::get_history_for_user)
+        .and(warp::get())
+        .and(warp::any().map(move || db_provider.clone()))
+        .and(warp::any().map(move || mngr.clone()))
+        .and_then(health::readiness_handler)
+}
+
+/// Route pattern of `path` as served by the filters above, used as a
+/// low-cardinality metrics label.
+pub fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["data"] => "/data",
+        ["data", _] => "/data/{id}",
+        ["login"] => "/login",
+        ["logout"] => "/logout",
+        ["sessions"] => "/sessions",
+        ["sessions", _] => "/sessions/{id}",
+        ["users"] => "/users",
+        ["users", _] => "/users/{login}",
+        ["history"] => "/history",
+        ["history", _] => "/history/{login}",
+        ["metrics"] => "/metrics",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        _ => "unmatched",
+    }
+}
+
+/// Complete route tree of the service.
+///
+/// Rejections are left to the caller, so the tree can be combined with
+/// other filters or mounted under a prefix before adding
+/// `.recover(problem::handle_rejection)` and `metrics::track_requests()`.
+pub async fn api(
+    db_provider: impl MongoDBProviderTrait + Clone + Sync,
+    mngr: impl LogMngTrait + Clone,
+    limits: Limits,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let data_path = warp::path("data");
+    let routes = data_path
+        .and(insert_filter_fcn(db_provider.clone(), mngr.clone()).await)
+        .or(data_path.and(list_filter_fcn(db_provider.clone(), mngr.clone()).await))
+        .or(data_path.and(get_filter_fcn(db_provider.clone(), mngr.clone()).await))
+        .or(data_path.and(put_filter_fcn(db_provider.clone(), mngr.clone()).await))
+        .or(data_path.and(patch_filter_fcn(db_provider.clone(), mngr.clone()).await))
+        .or(data_path.and(delete_filter_fcn(db_provider.clone(), mngr.clone()).await))
+        .or(login_filter_fcn(mngr.clone(), limits.logins.clone()).await)
+        .or(logout_filter_fcn(mngr.clone()).await)
+        .or(get_sessions_fcn(mngr.clone()).await)
+        .or(delete_certain_session(mngr.clone()).await)
+        .or(get_users_fcn(mngr.clone()).await)
+        .or(post_user_fcn(mngr.clone()).await)
+        .or(get_certain_user(mngr.clone()).await)
+        .or(update_certain_user(mngr.clone()).await)
+        .or(delete_certain_user(mngr.clone()).await)
+        .or(get_history_fcn(mngr.clone()).await);
+    // Probes and scrapes come from the orchestrator and monitoring, which
+    // must not be throttled into reporting the service as down.
+    let probes = metrics_fcn()
+        .await
+        .or(healthz_fcn().await)
+        .or(readyz_fcn(db_provider, mngr).await);
+    probes.or(ratelimit::limit(limits.requests).and(routes))
 }
@@ -2,17 +2,38 @@ table! {
     history (id) {
         id -> Text,
         login -> Text,
-        request -> Text,
         tms -> Timestamp,
+        action -> Text,
+        target_type -> Text,
+        target_id -> Nullable<Text>,
+        outcome -> Text,
+        status_code -> Nullable<Integer>,
+        latency_ms -> BigInt,
+        client_ip -> Nullable<Text>,
+        request_id -> Nullable<Text>,
+        payload -> Nullable<Text>,
+        token_fingerprint -> Nullable<Text>,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Text,
+        token_hash -> Text,
+        login -> Text,
+        created -> Timestamp,
+        expires -> Timestamp,
+        last_seen -> Timestamp,
+        user_agent -> Nullable<Text>,
     }
 }
 
 table! {
     users (login) {
         login -> Text,
-        password -> Text,
-        token -> Text,
+        password_hash -> Text,
+        role -> Text,
     }
 }
 
-allow_tables_to_appear_in_same_query!(history, users,);
+allow_tables_to_appear_in_same_query!(history, sessions, users,);
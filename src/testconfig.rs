@@ -0,0 +1,134 @@
+use std::{fs, path::PathBuf};
+
+use clap::Parser;
+
+use crate::config::{CliArgs, Config, ConfigError};
+
+fn write_config(name: &str, content: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}_{}.toml", name, std::process::id()));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+#[test]
+fn config_layering_test() {
+    let path = write_config(
+        "config_layering",
+        r#"
+            [server]
+            port = 8080
+
+            [mongo]
+            address = "mongo.local"
+            password = "secret"
+
+            [database]
+            url = "users.db"
+            pool_size = 4
+        "#,
+    );
+    let args = CliArgs::parse_from([
+        "server",
+        "--config",
+        path.to_str().unwrap(),
+        "--pool-size",
+        "8",
+        "--shutdown-timeout-secs",
+        "5",
+        "--rate-limit-rps",
+        "2.5",
+        "--history-retention-days",
+        "30",
+    ]);
+    let config = Config::load(&args).unwrap();
+    fs::remove_file(path).unwrap();
+
+    assert_eq!(config.server.port, 8080);
+    assert_eq!(config.server.bind_address, "0.0.0.0");
+    assert_eq!(config.mongo.address, "mongo.local");
+    assert_eq!(config.mongo.collection, "dobro");
+    assert_eq!(config.database.pool_size, 8);
+    assert_eq!(config.bind_address().1, 8080);
+    assert_eq!(config.shutdown_timeout(), std::time::Duration::from_secs(5));
+    assert_eq!(config.rate_limit.requests_per_second, 2.5);
+    assert_eq!(config.rate_limit.burst, 40);
+    assert_eq!(config.retention.max_age_days, Some(30));
+    assert_eq!(config.retention.max_rows_per_user, None);
+    assert!(config.retention.enabled());
+}
+
+#[test]
+fn config_validation_test() {
+    let args = CliArgs {
+        bind_address: Some("not an address".to_string()),
+        pool_size: Some(0),
+        ..CliArgs::default()
+    };
+    match Config::load(&args) {
+        Err(ConfigError::Invalid(problems)) => {
+            assert_eq!(problems.len(), 3);
+            assert!(problems[0].contains("server.bind_address"));
+            assert!(problems[1].contains("database.url"));
+            assert!(problems[2].contains("database.pool_size"));
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let path = write_config("config_unknown", "[server]\nprot = 1\n");
+    let args = CliArgs {
+        config: Some(path.clone()),
+        ..CliArgs::default()
+    };
+    let res = Config::load(&args);
+    fs::remove_file(path).unwrap();
+    assert!(matches!(res, Err(ConfigError::Parse(..))));
+
+    let mut config = Config::default();
+    config.database.url = "users.db".to_string();
+    config.rate_limit.burst = 0;
+    config.rate_limit.max_lockout_secs = 10;
+    config.retention.max_rows_per_user = Some(0);
+    config.retention.batch_size = 5000;
+    match config.validate() {
+        Err(ConfigError::Invalid(problems)) => {
+            assert_eq!(problems.len(), 4);
+            assert!(problems[0].contains("rate_limit.burst"));
+            assert!(problems[1].contains("rate_limit.lockout_secs"));
+            assert!(problems[2].contains("retention.max_rows_per_user"));
+            assert!(problems[3].contains("retention.batch_size"));
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn config_redaction_test() {
+    let mut config = Config::default();
+    config.mongo.password = "secret".to_string();
+    config.database.url = "postgres://user:pass@db:5432/users".to_string();
+    let printed = config.redacted().to_toml();
+    assert!(!printed.contains("secret"));
+    assert!(!printed.contains("pass@"));
+    assert!(printed.contains("postgres://user:***@db:5432/users"));
+}
+
+#[test]
+fn migrate_flags_conflict_test() {
+    let args = CliArgs::try_parse_from(["server", "--migrate-only"]).unwrap();
+    assert!(args.migrate_only);
+    assert!(!args.no_migrate);
+    assert!(CliArgs::try_parse_from(["server", "--migrate-only", "--no-migrate"]).is_err());
+}
+
+#[test]
+fn subcommand_parse_test() {
+    let args = CliArgs::try_parse_from(["server", "user", "list", "--config", "app.toml"]).unwrap();
+    assert!(args.command.is_some());
+    assert_eq!(args.config, Some(PathBuf::from("app.toml")));
+    assert!(CliArgs::try_parse_from(["server"])
+        .unwrap()
+        .command
+        .is_none());
+    assert!(CliArgs::try_parse_from(["server", "user", "add"]).is_err());
+    assert!(CliArgs::try_parse_from(["server", "user", "add", "bob", "--role", "boss"]).is_err());
+}
@@ -3,119 +3,299 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use warp::hyper::StatusCode;
+use async_trait::async_trait;
+use clap::Parser;
+use uuid::Uuid;
+use warp::{hyper::StatusCode, Filter};
 
 use crate::{
-    loginmanager::{LogMngTrait, SimplifiedUser},
-    models::User,
-    routes,
+    audit::{
+        self, AuditEntry, AuditEvent, AuditRecord, HistoryPage, HistoryQuery, Outcome, SortOrder,
+    },
+    bootstrap::{self, Bootstrap},
+    cli,
+    config::{AuditConfig, CliArgs, RetentionConfig},
+    dbconnection::DbConnection,
+    loginerror::LoginError,
+    loginmanager::{
+        LogMngTrait, LoginManager, NewSession, PublicUser, SessionInfo, SimplifiedUser, UserUpdate,
+    },
+    metrics,
+    migrations::{self, MigrationError},
+    models::{History, Session, User},
+    passwordhash::{self, PasswordCheck},
+    permissions::{AuthError, Credential, Identity, Role},
+    problem,
+    ratelimit::{self, Limits, LoginLockout, RateLimiter},
+    retention, routes,
 };
-#[derive(Clone)]
+/// Successful request of `login` from 127.0.0.1.
+pub fn audit_entry(login: &str, event: AuditEvent) -> AuditEntry {
+    AuditEntry {
+        login: login.to_string(),
+        event,
+        status: StatusCode::OK,
+        latency: std::time::Duration::from_millis(3),
+        client_ip: Some([127, 0, 0, 1].into()),
+        request_id: "req-1".to_string(),
+        token_fingerprint: None,
+    }
+}
+
+/// `minute` past 2022-04-01 10:00.
+pub fn history_time(minute: u32) -> chrono::NaiveDateTime {
+    chrono::NaiveDate::from_ymd_opt(2022, 4, 1)
+        .and_then(|x| x.and_hms_opt(10, minute, 0))
+        .unwrap()
+}
+
+/// Stored history row of `login` at `history_time(minute)`.
+pub fn history_at(login: &str, event: AuditEvent, minute: u32) -> History {
+    History {
+        tms: history_time(minute),
+        ..audit_entry(login, event).to_history(&AuditConfig::default())
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct MockLogMngr {
     pub inner: Arc<RwLock<BTreeMap<String, User>>>,
+    /// Sessions keyed by their plain token.
+    pub sessions: Arc<RwLock<BTreeMap<String, Session>>>,
+    pub audit: Arc<RwLock<Vec<AuditEntry>>>,
+    /// `audit` as stored, so that times and ids stay stable across queries.
+    pub history: Arc<RwLock<Vec<AuditRecord>>>,
 }
+
+impl MockLogMngr {
+    /// Creates a user and logs it in, returning the session token.
+    pub async fn with_user(&self, login: &str, password: &str, role: Role) -> String {
+        self.insert_new_user(SimplifiedUser {
+            login: login.to_string(),
+            password: password.to_string(),
+            role: Some(role),
+        })
+        .await
+        .unwrap();
+        self.new_session(login.to_string(), None).token
+    }
+
+    fn new_session(&self, login: String, user_agent: Option<String>) -> NewSession {
+        let token = Uuid::new_v4().to_string();
+        let now = chrono::Utc::now().naive_utc();
+        let session = Session {
+            id: Uuid::new_v4().to_string(),
+            token_hash: token.clone(),
+            login,
+            created: now,
+            expires: now + chrono::Duration::hours(1),
+            last_seen: now,
+            user_agent,
+        };
+        let new_session = NewSession {
+            id: session.id.clone(),
+            token: token.clone(),
+            expires: session.expires,
+        };
+        self.sessions.write().unwrap().insert(token, session);
+        new_session
+    }
+
+    fn find_session(&self, token: &str) -> Option<Session> {
+        let tmp = self.sessions.read().unwrap();
+        tmp.get(token)
+            .filter(|x| x.expires > chrono::Utc::now().naive_utc())
+            .cloned()
+    }
+}
+
+#[async_trait]
 impl LogMngTrait for MockLogMngr {
-    fn check_user(&self, user: String, pass: String) -> bool {
+    async fn check_user(&self, user: String, pass: String) -> Result<bool, LoginError> {
         let tmp = self.inner.read().unwrap();
-        match tmp.get(&user) {
+        Ok(match tmp.get(&user) {
             None => false,
-            Some(user) => pass == user.password,
-        }
+            Some(user) => {
+                passwordhash::verify_password(&pass, &user.password_hash) != PasswordCheck::Invalid
+            }
+        })
     }
-    fn get_users_list(&self) -> Result<Vec<SimplifiedUser>, diesel::result::Error> {
-        let mut res: Vec<SimplifiedUser> = Vec::new();
+    async fn get_users_list(&self) -> Result<Vec<PublicUser>, LoginError> {
         let tmp = self.inner.read().unwrap();
-        let iterat = tmp.iter();
-        for (log, user) in iterat {
-            res.push(SimplifiedUser {
-                login: log.to_string(),
-                password: user.password.to_string(),
-            });
-        }
-        Ok(res)
+        Ok(tmp.values().cloned().map(PublicUser::from).collect())
     }
-    fn insert_new_user(&self, new_user: SimplifiedUser) -> bool {
+    async fn insert_new_user(&self, new_user: SimplifiedUser) -> Result<bool, LoginError> {
         let mut tmp = self.inner.write().unwrap();
         if tmp.contains_key(&new_user.login) {
-            false
+            Ok(false)
         } else {
             let tmp_user = new_user.clone();
             tmp.insert(
                 new_user.login.clone(),
                 User {
                     login: tmp_user.login,
-                    password: tmp_user.password,
-                    token: new_user.login,
+                    password_hash: passwordhash::hash_password(&tmp_user.password).unwrap(),
+                    role: tmp_user.role.unwrap_or_default().as_str().to_string(),
                 },
             );
-            true
+            Ok(true)
         }
     }
-    fn get_by_login(&self, login: String) -> Option<SimplifiedUser> {
+    async fn get_by_login(&self, login: String) -> Result<Option<PublicUser>, LoginError> {
         let tmp = self.inner.read().unwrap();
-        if tmp.contains_key(&login) {
-            let pass = tmp.get(&login).unwrap();
-            Some(SimplifiedUser {
-                login,
-                password: pass.password.to_string(),
-            })
-        } else {
-            None
-        }
+        Ok(tmp.get(&login).cloned().map(PublicUser::from))
     }
-    fn update_password(&self, new_data: SimplifiedUser) -> bool {
+    async fn update_user(&self, update: UserUpdate) -> Result<bool, LoginError> {
         let mut tmp = self.inner.write().unwrap();
-        if tmp.contains_key(&new_data.login) {
-            let mut data = tmp.get_mut(&new_data.login).unwrap();
-            data.password = new_data.password;
-            true
+        if tmp.contains_key(&update.login) {
+            let data = tmp.get_mut(&update.login).unwrap();
+            if let Some(password) = &update.password {
+                data.password_hash = passwordhash::hash_password(password).unwrap();
+            }
+            if let Some(role) = update.role {
+                data.role = role.as_str().to_string();
+            }
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
-    fn delete_user(&self, login: String) -> bool {
+    async fn delete_user(&self, login: String) -> Result<bool, LoginError> {
         let mut tmp = self.inner.write().unwrap();
         if tmp.contains_key(&login) {
             tmp.remove(&login);
-            true
+            self.sessions
+                .write()
+                .unwrap()
+                .retain(|_, session| session.login != login);
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
-    fn get_security_key(&self, username: String) -> String {
-        let tmp = self.inner.read().unwrap();
-        tmp.get(&username).unwrap().token.clone()
+    async fn create_session(
+        &self,
+        login: String,
+        user_agent: Option<String>,
+    ) -> Result<NewSession, LoginError> {
+        Ok(self.new_session(login, user_agent))
     }
 
-    fn check_token(&self, _token: String, _req: String) -> bool {
-        true
+    async fn check_token(&self, token: String) -> Result<bool, LoginError> {
+        Ok(self.find_session(&token).is_some())
     }
 
-    fn get_history(
-        &self,
-        login: String,
-    ) -> Result<Vec<crate::models::History>, diesel::result::Error> {
+    async fn authenticate(&self, credential: Credential) -> Result<Identity, AuthError> {
+        let login = match credential {
+            Credential::Token(token) => {
+                self.find_session(&token)
+                    .ok_or(AuthError::InvalidToken)?
+                    .login
+            }
+            Credential::ClientCert(login) => login,
+        };
         let tmp = self.inner.read().unwrap();
-        if tmp.contains_key(&login) {
-            Ok(Vec::new())
-        } else {
-            Err(diesel::result::Error::NotFound)
-        }
+        let user = tmp.get(&login).ok_or(AuthError::InvalidToken)?;
+        Ok(Identity {
+            login,
+            role: user.role(),
+        })
+    }
+
+    async fn logout(&self, token: String) -> Result<bool, LoginError> {
+        Ok(self.sessions.write().unwrap().remove(&token).is_some())
+    }
+
+    async fn get_sessions(&self, token: String) -> Result<Option<Vec<SessionInfo>>, LoginError> {
+        let login = match self.find_session(&token) {
+            Some(session) => session.login,
+            None => return Ok(None),
+        };
+        let tmp = self.sessions.read().unwrap();
+        Ok(Some(
+            tmp.values()
+                .filter(|x| x.login == login)
+                .cloned()
+                .map(SessionInfo::from)
+                .collect(),
+        ))
+    }
+
+    async fn revoke_session(&self, token: String, session_id: String) -> Result<bool, LoginError> {
+        let login = match self.find_session(&token) {
+            Some(session) => session.login,
+            None => return Ok(false),
+        };
+        let mut tmp = self.sessions.write().unwrap();
+        let before = tmp.len();
+        tmp.retain(|_, x| !(x.id == session_id && x.login == login));
+        Ok(tmp.len() != before)
+    }
+
+    async fn record_audit(&self, entry: AuditEntry) -> Result<(), LoginError> {
+        self.history
+            .write()
+            .unwrap()
+            .push(AuditRecord::from(entry.to_history(&AuditConfig::default())));
+        self.audit.write().unwrap().push(entry);
+        Ok(())
+    }
+
+    async fn query_history(&self, query: HistoryQuery) -> Result<HistoryPage, LoginError> {
+        let cursor = query.decoded_cursor()?;
+        let order = query.order();
+        let mut items: Vec<AuditRecord> = self
+            .history
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|x| {
+                query.login.iter().all(|login| *login == x.login)
+                    && query.from.iter().all(|from| x.time >= *from)
+                    && query.to.iter().all(|to| x.time < *to)
+                    && query.action.iter().all(|action| *action == x.action)
+            })
+            .filter(|x| {
+                cursor.iter().all(|cursor| {
+                    let key = (x.time, &x.id);
+                    let after = (cursor.time, &cursor.id);
+                    match order {
+                        SortOrder::Asc => key > after,
+                        SortOrder::Desc => key < after,
+                    }
+                })
+            })
+            .cloned()
+            .collect();
+        items.sort_by(|a, b| {
+            let ord = (a.time, &a.id).cmp(&(b.time, &b.id));
+            match order {
+                SortOrder::Asc => ord,
+                SortOrder::Desc => ord.reverse(),
+            }
+        });
+        items.truncate(query.page_size() as usize + 1);
+        Ok(query.make_page(items))
+    }
+
+    async fn ping(&self) -> Result<(), LoginError> {
+        Ok(())
     }
 }
 #[tokio::test]
 async fn login_route_test() {
-    tracing_subscriber::fmt().try_init().unwrap_or_else(|_| {});
-    let mngr = MockLogMngr {
-        inner: Arc::new(RwLock::new(BTreeMap::new())),
-    };
+    tracing_subscriber::fmt().try_init().unwrap_or(());
+    let mngr = MockLogMngr::default();
     let test_stuct = SimplifiedUser {
         login: "123".to_string(),
         password: "321".to_string(),
+        role: Some(Role::Admin),
     };
-    mngr.insert_new_user(test_stuct.clone());
-    let data_path_routes = routes::login_filter_fcn(mngr.clone()).await;
+    mngr.insert_new_user(test_stuct.clone()).await.unwrap();
+    let data_path_routes = routes::login_filter_fcn(mngr.clone(), Limits::default().logins)
+        .await
+        .recover(problem::handle_rejection);
 
     let req_test = warp::test::request()
         .path("/login")
@@ -124,10 +304,9 @@ async fn login_route_test() {
         .reply(&data_path_routes.clone())
         .await;
     assert_eq!(req_test.status(), StatusCode::OK);
-    assert_eq!(
-        req_test.headers().get("token").unwrap().to_str().unwrap(),
-        mngr.get_security_key(test_stuct.login.clone())
-    );
+    let token = req_test.headers().get("token").unwrap().to_str().unwrap();
+    assert!(mngr.check_token(token.to_string()).await.unwrap());
+    assert!(req_test.headers().contains_key("session-id"));
     let test_login = test_stuct.login.clone();
     let req_test = warp::test::request()
         .path("/login")
@@ -135,30 +314,53 @@ async fn login_route_test() {
         .header("password", "ABC")
         .reply(&data_path_routes.clone())
         .await;
-    assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+    assert_eq!(req_test.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        req_test.headers()["content-type"],
+        problem::PROBLEM_CONTENT_TYPE
+    );
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.code, "invalid_credentials");
+    assert_eq!(problem.status, 401);
+
+    let req_test = warp::test::request()
+        .path("/login")
+        .header("login", &test_login)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::BAD_REQUEST);
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.code, "missing_header");
 }
 
 #[tokio::test]
 async fn get_users_route_test() {
-    tracing_subscriber::fmt().try_init().unwrap_or_else(|_| {});
-    let mngr = MockLogMngr {
-        inner: Arc::new(RwLock::new(BTreeMap::new())),
-    };
+    tracing_subscriber::fmt().try_init().unwrap_or(());
+    let mngr = MockLogMngr::default();
     let test_stuct = SimplifiedUser {
         login: "123".to_string(),
         password: "321".to_string(),
+        role: Some(Role::Admin),
     };
     let test_struct = SimplifiedUser {
         login: "ABC".to_string(),
         password: "CBA".to_string(),
+        role: Some(Role::Admin),
     };
-    mngr.insert_new_user(test_stuct.clone());
-    mngr.insert_new_user(test_struct.clone());
-    let data_path_routes = routes::get_users_fcn(mngr.clone()).await;
+    mngr.insert_new_user(test_stuct.clone()).await.unwrap();
+    mngr.insert_new_user(test_struct.clone()).await.unwrap();
+    let token = mngr
+        .create_session(test_stuct.login.clone(), None)
+        .await
+        .unwrap()
+        .token;
+    let data_path_routes = routes::get_users_fcn(mngr.clone())
+        .await
+        .recover(problem::handle_rejection);
     let req_test = warp::test::request()
         .path("/users")
         .method("GET")
-        .header("autorization", "123")
+        .header("autorization", &token)
         .reply(&data_path_routes.clone())
         .await;
     assert_eq!(req_test.status(), StatusCode::OK);
@@ -166,41 +368,1411 @@ async fn get_users_route_test() {
 
     let encoded = std::str::from_utf8(&body).unwrap();
 
-    let test_vec = vec![test_stuct, test_struct];
+    let test_vec = vec![
+        PublicUser {
+            login: test_stuct.login,
+            role: Role::Admin,
+        },
+        PublicUser {
+            login: test_struct.login,
+            role: Role::Admin,
+        },
+    ];
     assert_eq!(encoded, serde_json::to_string(&test_vec).unwrap());
+    assert!(!encoded.contains(&test_stuct.password));
 }
 
 #[tokio::test]
 async fn post_users_route_test() {
-    tracing_subscriber::fmt().try_init().unwrap_or_else(|_| {});
-    let mngr = MockLogMngr {
-        inner: Arc::new(RwLock::new(BTreeMap::new())),
-    };
+    tracing_subscriber::fmt().try_init().unwrap_or(());
+    let mngr = MockLogMngr::default();
     let test_stuct = SimplifiedUser {
         login: "123".to_string(),
         password: "321".to_string(),
+        role: Some(Role::Admin),
     };
     let test_struct = SimplifiedUser {
         login: "ABC".to_string(),
         password: "CBA".to_string(),
+        role: Some(Role::Admin),
     };
-    mngr.insert_new_user(test_stuct.clone());
-    let data_path_routes = routes::post_user_fcn(mngr.clone()).await;
+    mngr.insert_new_user(test_stuct.clone()).await.unwrap();
+    let token = mngr
+        .create_session(test_stuct.login.clone(), None)
+        .await
+        .unwrap()
+        .token;
+    let data_path_routes = routes::post_user_fcn(mngr.clone())
+        .await
+        .recover(problem::handle_rejection);
     let req_test = warp::test::request()
         .path("/users")
         .method("POST")
-        .header("autorization", "123")
+        .header("autorization", &token)
         .body(serde_json::to_string(&test_stuct).unwrap())
         .reply(&data_path_routes.clone())
         .await;
-    assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+    assert_eq!(req_test.status(), StatusCode::CONFLICT);
 
     let req_test = warp::test::request()
         .path("/users")
         .method("POST")
-        .header("autorization", "123")
+        .header("autorization", &token)
         .body(serde_json::to_string(&test_struct).unwrap())
         .reply(&data_path_routes.clone())
         .await;
     assert_eq!(req_test.status(), StatusCode::OK);
 }
+
+#[tokio::test]
+async fn logout_route_test() {
+    tracing_subscriber::fmt().try_init().unwrap_or(());
+    let mngr = MockLogMngr::default();
+    let token = mngr.with_user("123", "321", Role::Admin).await;
+    let other_token = mngr
+        .create_session("123".to_string(), None)
+        .await
+        .unwrap()
+        .token;
+    let data_path_routes = routes::logout_filter_fcn(mngr.clone())
+        .await
+        .recover(problem::handle_rejection);
+    let req_test = warp::test::request()
+        .path("/logout")
+        .method("POST")
+        .header("autorization", &token)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::NO_CONTENT);
+    assert!(!mngr.check_token(token.clone()).await.unwrap());
+    assert!(mngr.check_token(other_token).await.unwrap());
+
+    let req_test = warp::test::request()
+        .path("/logout")
+        .method("POST")
+        .header("autorization", &token)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn sessions_route_test() {
+    tracing_subscriber::fmt().try_init().unwrap_or(());
+    let mngr = MockLogMngr::default();
+    let token = mngr.with_user("123", "321", Role::Admin).await;
+    let second = mngr
+        .create_session("123".to_string(), Some("phone".to_string()))
+        .await
+        .unwrap();
+    let foreign = mngr.with_user("ABC", "CBA", Role::Reader).await;
+    let data_path_routes = routes::get_sessions_fcn(mngr.clone())
+        .await
+        .or(routes::delete_certain_session(mngr.clone()).await)
+        .recover(problem::handle_rejection);
+
+    let req_test = warp::test::request()
+        .path("/sessions")
+        .method("GET")
+        .header("autorization", &token)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+    let sessions: Vec<SessionInfo> = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(sessions.len(), 2);
+    assert!(!std::str::from_utf8(req_test.body())
+        .unwrap()
+        .contains(&token));
+
+    let req_test = warp::test::request()
+        .path(&format!("/sessions/{}", second.id))
+        .method("DELETE")
+        .header("autorization", &foreign)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+    assert!(mngr.check_token(second.token.clone()).await.unwrap());
+
+    let req_test = warp::test::request()
+        .path(&format!("/sessions/{}", second.id))
+        .method("DELETE")
+        .header("autorization", &token)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::NO_CONTENT);
+    assert!(!mngr.check_token(second.token).await.unwrap());
+    assert!(mngr.check_token(token).await.unwrap());
+}
+
+#[tokio::test]
+async fn users_route_permissions_test() {
+    tracing_subscriber::fmt().try_init().unwrap_or(());
+    let mngr = MockLogMngr::default();
+    let admin = mngr.with_user("admin", "admin", Role::Admin).await;
+    let writer = mngr.with_user("writer", "writer", Role::Writer).await;
+    let reader = mngr.with_user("reader", "reader", Role::Reader).await;
+    let data_path_routes = routes::get_users_fcn(mngr.clone())
+        .await
+        .or(routes::get_certain_user(mngr.clone()).await)
+        .or(routes::update_certain_user(mngr.clone()).await)
+        .or(routes::delete_certain_user(mngr.clone()).await)
+        .recover(problem::handle_rejection);
+
+    let req_test = warp::test::request()
+        .path("/users")
+        .method("GET")
+        .header("autorization", &reader)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.code, "forbidden");
+    assert_eq!(problem.detail, "Missing permission read_users");
+
+    let req_test = warp::test::request()
+        .path("/users/reader")
+        .method("GET")
+        .header("autorization", &reader)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+
+    let req_test = warp::test::request()
+        .path("/users/writer")
+        .method("GET")
+        .header("autorization", &reader)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+
+    let own_update = SimplifiedUser {
+        login: "reader".to_string(),
+        password: "new".to_string(),
+        role: None,
+    };
+    let req_test = warp::test::request()
+        .path("/users/reader")
+        .method("PUT")
+        .header("autorization", &reader)
+        .body(serde_json::to_string(&own_update).unwrap())
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+    assert!(mngr
+        .check_user("reader".to_string(), "new".to_string())
+        .await
+        .unwrap());
+
+    let escalation = SimplifiedUser {
+        role: Some(Role::Admin),
+        ..own_update
+    };
+    let req_test = warp::test::request()
+        .path("/users/reader")
+        .method("PUT")
+        .header("autorization", &reader)
+        .body(serde_json::to_string(&escalation).unwrap())
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.detail, "Missing permission write_users");
+
+    let req_test = warp::test::request()
+        .path("/users/reader")
+        .method("DELETE")
+        .header("autorization", &writer)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+
+    let req_test = warp::test::request()
+        .path("/users/reader")
+        .method("PUT")
+        .header("autorization", &admin)
+        .body(serde_json::to_string(&escalation).unwrap())
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+    assert_eq!(
+        mngr.get_by_login("reader".to_string())
+            .await
+            .unwrap()
+            .unwrap()
+            .role,
+        Role::Admin
+    );
+}
+
+#[tokio::test]
+async fn history_route_test() {
+    tracing_subscriber::fmt().try_init().unwrap_or(());
+    let mngr = MockLogMngr::default();
+    let admin = mngr.with_user("admin", "admin", Role::Admin).await;
+    let reader = mngr.with_user("reader", "reader", Role::Reader).await;
+    mngr.with_user("writer", "writer", Role::Writer).await;
+    let data_path_routes = routes::get_history_fcn(mngr.clone())
+        .await
+        .recover(problem::handle_rejection);
+
+    let req_test = warp::test::request()
+        .path("/history/writer")
+        .method("GET")
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::BAD_REQUEST);
+
+    let req_test = warp::test::request()
+        .path("/history/writer")
+        .method("GET")
+        .header("autorization", "not a token")
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+
+    let req_test = warp::test::request()
+        .path("/history/writer")
+        .method("GET")
+        .header("autorization", &reader)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.code, "forbidden");
+    assert_eq!(problem.detail, "Missing permission read_history");
+
+    let req_test = warp::test::request()
+        .path("/history/reader")
+        .method("GET")
+        .header("autorization", &reader)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+
+    let req_test = warp::test::request()
+        .path("/history/writer")
+        .method("GET")
+        .header("autorization", &admin)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+
+    for path in ["/history", "/history?login=writer"] {
+        let req_test = warp::test::request()
+            .path(path)
+            .header("autorization", &reader)
+            .reply(&data_path_routes.clone())
+            .await;
+        assert_eq!(req_test.status(), StatusCode::FORBIDDEN);
+    }
+
+    let req_test = warp::test::request()
+        .path("/history?login=reader")
+        .header("autorization", &reader)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+    let page: HistoryPage = serde_json::from_slice(req_test.body()).unwrap();
+    assert!(page.items.iter().all(|x| x.login == "reader"));
+
+    mngr.history
+        .write()
+        .unwrap()
+        .extend(
+            [("reader", 1), ("writer", 2), ("reader", 3)].map(|(login, minute)| {
+                AuditRecord::from(history_at(login, AuditEvent::ListUsers, minute))
+            }),
+        );
+    let page_of = |path: String| {
+        let data_path_routes = data_path_routes.clone();
+        let admin = admin.clone();
+        async move {
+            let req_test = warp::test::request()
+                .path(&path)
+                .header("autorization", &admin)
+                .reply(&data_path_routes)
+                .await;
+            assert_eq!(req_test.status(), StatusCode::OK);
+            serde_json::from_slice::<HistoryPage>(req_test.body()).unwrap()
+        }
+    };
+    let first = page_of("/history?action=list_users&limit=2".to_string()).await;
+    assert_eq!(
+        first
+            .items
+            .iter()
+            .map(|x| x.login.as_str())
+            .collect::<Vec<_>>(),
+        vec!["reader", "writer"]
+    );
+    assert!(first.items[0].time > first.items[1].time);
+    let second = page_of(format!(
+        "/history?action=list_users&limit=2&cursor={}",
+        first.next_cursor.unwrap()
+    ))
+    .await;
+    assert_eq!(second.items.len(), 1);
+    assert_eq!(second.next_cursor, None);
+    let ranged = page_of(
+        "/history/reader?action=list_users&order=asc&from=2022-04-01T10:00:00&to=2022-04-01T10:03:00"
+            .to_string(),
+    )
+    .await;
+    assert_eq!(ranged.items.len(), 1);
+    assert_eq!(ranged.items[0].time.format("%M").to_string(), "01");
+
+    let req_test = warp::test::request()
+        .path("/history?cursor=nonsense")
+        .header("autorization", &admin)
+        .reply(&data_path_routes.clone())
+        .await;
+    assert_eq!(req_test.status(), StatusCode::BAD_REQUEST);
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.code, "invalid_query");
+}
+
+#[test]
+fn password_hash_test() {
+    let hash = passwordhash::hash_password("bandit").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert_ne!(hash, passwordhash::hash_password("bandit").unwrap());
+    assert_eq!(
+        passwordhash::verify_password("bandit", &hash),
+        PasswordCheck::Valid
+    );
+    assert_eq!(
+        passwordhash::verify_password("toad", &hash),
+        PasswordCheck::Invalid
+    );
+}
+
+#[test]
+fn password_rehash_test() {
+    assert_eq!(
+        passwordhash::verify_password("admin", "admin"),
+        PasswordCheck::NeedsRehash
+    );
+    assert_eq!(
+        passwordhash::verify_password("bandit", "admin"),
+        PasswordCheck::Invalid
+    );
+    let weak_hash = "$argon2id$v=19$m=1024,t=1,p=1$+FO42XlUdlUnPQxmn9CZKw$mNgIoiRpZVqsY51KDWmoEdUsgVViYSccC1a28a+X5gw";
+    assert_eq!(
+        passwordhash::verify_password("admin", weak_hash),
+        PasswordCheck::NeedsRehash
+    );
+}
+
+/// Fresh SQLite user store with every migration applied.
+fn sqlite_login_manager(name: &str, pool_size: u32) -> (LoginManager, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("{}_{}.db", name, std::process::id()));
+    std::fs::remove_file(&path).unwrap_or(());
+    migrations::run(&DbConnection::establish(path.to_str().unwrap()).unwrap()).unwrap();
+    (
+        LoginManager::with_pool_size(path.to_str().unwrap().to_string(), pool_size).unwrap(),
+        path,
+    )
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn sqlite_concurrent_token_check_test() {
+    let (mngr, path) = sqlite_login_manager("concurrent_token_check", 4);
+    assert_eq!(mngr.ping().await, Ok(()));
+    let token = mngr
+        .create_session("admin".to_string(), None)
+        .await
+        .unwrap()
+        .token;
+    let checks = (0..32).map(|_| {
+        let mngr = mngr.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            assert_eq!(mngr.check_token(token).await, Ok(true));
+            mngr.record_audit(audit_entry("admin", AuditEvent::ListSessions))
+                .await
+        })
+    });
+    for check in futures_util::future::join_all(checks).await {
+        assert_eq!(check.unwrap(), Ok(()));
+    }
+    assert_eq!(
+        mngr.query_history(HistoryQuery {
+            login: Some("admin".to_string()),
+            limit: Some(100),
+            ..HistoryQuery::default()
+        })
+        .await
+        .unwrap()
+        .items
+        .len(),
+        32
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn sqlite_update_user_test() {
+    let (mngr, path) = sqlite_login_manager("update_user", 1);
+    mngr.insert_new_user(SimplifiedUser {
+        login: "bob".to_string(),
+        password: "bob".to_string(),
+        role: Some(Role::Reader),
+    })
+    .await
+    .unwrap();
+    let role_only: UserUpdate =
+        serde_json::from_value(serde_json::json!({"login": "bob", "role": "writer"})).unwrap();
+    assert_eq!(mngr.update_user(role_only).await, Ok(true));
+    let user = mngr.get_by_login("bob".to_string()).await.unwrap().unwrap();
+    assert_eq!(user.role, Role::Writer);
+    assert_eq!(
+        mngr.check_user("bob".to_string(), "bob".to_string()).await,
+        Ok(true)
+    );
+
+    let password_only = UserUpdate {
+        login: "bob".to_string(),
+        password: Some("new".to_string()),
+        role: None,
+    };
+    assert_eq!(mngr.update_user(password_only.clone()).await, Ok(true));
+    assert_eq!(
+        mngr.check_user("bob".to_string(), "new".to_string()).await,
+        Ok(true)
+    );
+    let user = mngr.get_by_login("bob".to_string()).await.unwrap().unwrap();
+    assert_eq!(user.role, Role::Writer);
+
+    assert_eq!(
+        mngr.update_user(UserUpdate {
+            login: "nobody".to_string(),
+            ..password_only
+        })
+        .await,
+        Ok(false)
+    );
+    assert_eq!(
+        mngr.update_user(UserUpdate {
+            login: "nobody".to_string(),
+            password: None,
+            role: None,
+        })
+        .await,
+        Ok(false)
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn sqlite_delete_user_test() {
+    let (mngr, path) = sqlite_login_manager("delete_user", 1);
+    bootstrap::bootstrap_admin(&mngr, None, Some("secret".to_string()))
+        .await
+        .unwrap();
+    mngr.insert_new_user(SimplifiedUser {
+        login: "bob".to_string(),
+        password: "bob".to_string(),
+        role: Some(Role::Reader),
+    })
+    .await
+    .unwrap();
+    let bob = mngr.create_session("bob".to_string(), None).await.unwrap();
+    assert_eq!(mngr.delete_user("bob".to_string()).await, Ok(true));
+    assert_eq!(mngr.check_token(bob.token).await, Ok(false));
+    assert_eq!(mngr.delete_user("bob".to_string()).await, Ok(false));
+
+    let admin = mngr
+        .create_session("admin".to_string(), None)
+        .await
+        .unwrap();
+    let data_path_routes = routes::delete_certain_user(mngr.clone())
+        .await
+        .recover(problem::handle_rejection);
+    let req_test = warp::test::request()
+        .path("/users/nobody")
+        .method("DELETE")
+        .header("autorization", &admin.token)
+        .reply(&data_path_routes)
+        .await;
+    assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.code, "user_not_found");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn sqlite_checkout_timeout_test() {
+    let (mngr, path) = sqlite_login_manager("checkout_timeout", 1);
+    bootstrap::bootstrap_admin(&mngr, None, Some("admin".to_string()))
+        .await
+        .unwrap();
+    let mngr = mngr.with_checkout_timeout(std::time::Duration::from_millis(20));
+    // Password verification keeps the only connection busy well past the timeout.
+    let checks = (0..4).map(|_| {
+        let mngr = mngr.clone();
+        tokio::spawn(async move {
+            mngr.check_user("admin".to_string(), "admin".to_string())
+                .await
+        })
+    });
+    let results: Vec<_> = futures_util::future::join_all(checks)
+        .await
+        .into_iter()
+        .map(|x| x.unwrap())
+        .collect();
+    assert!(results.contains(&Ok(true)));
+    let err = results
+        .into_iter()
+        .find_map(|x| x.err())
+        .expect("expected a checkout timeout");
+    assert!(matches!(err, LoginError::Unavailable(_)));
+    assert_eq!(
+        problem::Problem::from(err).status_code(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn sqlite_missing_database_test() {
+    let path = std::env::temp_dir()
+        .join(format!("missing_dir_{}", std::process::id()))
+        .join("users.db");
+    let err = LoginManager::with_pool_size(path.to_str().unwrap().to_string(), 1)
+        .err()
+        .expect("expected the pool build to fail");
+    assert!(matches!(err, LoginError::Unavailable(_)));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn sqlite_locked_database_test() {
+    use diesel::{connection::SimpleConnection, Connection};
+
+    let (mngr, path) = sqlite_login_manager("locked_database", 1);
+    let token = mngr
+        .create_session("admin".to_string(), None)
+        .await
+        .unwrap()
+        .token;
+    let data_path_routes = routes::get_users_fcn(mngr.clone())
+        .await
+        .recover(problem::handle_rejection);
+
+    let locker = diesel::SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+    locker.batch_execute("BEGIN EXCLUSIVE;").unwrap();
+    assert!(matches!(
+        mngr.check_token(token.clone()).await,
+        Err(LoginError::Unavailable(_))
+    ));
+    let req_test = warp::test::request()
+        .path("/users")
+        .method("GET")
+        .header("autorization", &token)
+        .reply(&data_path_routes)
+        .await;
+    assert_eq!(req_test.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(body.code, "store_unavailable");
+
+    locker.batch_execute("ROLLBACK;").unwrap();
+    assert_eq!(mngr.check_token(token).await, Ok(true));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn sqlite_migrations_test() {
+    use diesel::connection::SimpleConnection;
+
+    let path = std::env::temp_dir().join(format!("migrations_{}.db", std::process::id()));
+    std::fs::remove_file(&path).unwrap_or(());
+    let url = path.to_str().unwrap();
+
+    migrations::prepare_database(url, false).unwrap();
+    let conn = DbConnection::establish(url).unwrap();
+    let embedded = migrations::embedded(conn.backend());
+    assert_eq!(migrations::pending(&conn).unwrap().len(), embedded.len());
+
+    migrations::prepare_database(url, true).unwrap();
+    assert!(migrations::pending(&conn).unwrap().is_empty());
+    assert!(User::get_list(&conn).unwrap().is_empty());
+    assert_eq!(migrations::run(&conn), Ok(Vec::new()));
+
+    conn.batch_execute(
+        "INSERT INTO __diesel_schema_migrations (version) VALUES ('99991231000000')",
+    )
+    .unwrap();
+    assert_eq!(
+        migrations::prepare_database(url, true),
+        Err(MigrationError::SchemaTooNew(vec![
+            "99991231000000".to_string()
+        ]))
+    );
+    assert!(migrations::prepare_database(url, false).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn bootstrap_admin_test() {
+    let mngr = MockLogMngr::default();
+    let password = match bootstrap::bootstrap_admin(&mngr, None, None).await {
+        Ok(Bootstrap::Generated { login, password }) => {
+            assert_eq!(login, bootstrap::DEFAULT_ADMIN_LOGIN);
+            password
+        }
+        other => panic!("unexpected bootstrap result {:?}", other),
+    };
+    assert_eq!(password.len(), 24);
+    assert!(mngr
+        .check_user("admin".to_string(), password)
+        .await
+        .unwrap());
+    assert_eq!(
+        mngr.get_by_login("admin".to_string())
+            .await
+            .unwrap()
+            .unwrap()
+            .role,
+        Role::Admin
+    );
+    assert_eq!(
+        bootstrap::bootstrap_admin(&mngr, None, None).await,
+        Ok(Bootstrap::Skipped)
+    );
+
+    let mngr = MockLogMngr::default();
+    assert_eq!(
+        bootstrap::bootstrap_admin(&mngr, Some("root".to_string()), Some("s3cret".to_string()))
+            .await,
+        Ok(Bootstrap::Created {
+            login: "root".to_string()
+        })
+    );
+    assert!(mngr
+        .check_user("root".to_string(), "s3cret".to_string())
+        .await
+        .unwrap());
+
+    // Users but no administrator, e.g. after the seeded admin was removed.
+    let mngr = MockLogMngr::default();
+    mngr.with_user("bob", "bob", Role::Reader).await;
+    assert!(matches!(
+        bootstrap::bootstrap_admin(&mngr, None, None).await,
+        Ok(Bootstrap::Generated { .. })
+    ));
+    let mngr = MockLogMngr::default();
+    mngr.with_user("admin", "admin", Role::Reader).await;
+    assert!(matches!(
+        bootstrap::bootstrap_admin(&mngr, None, None).await,
+        Err(LoginError::Invalid(_))
+    ));
+}
+
+#[tokio::test]
+async fn sqlite_bootstrap_after_seed_removal_test() {
+    use diesel::connection::SimpleConnection;
+    let (mngr, path) = sqlite_login_manager("bootstrap_after_seed_removal", 1);
+    let conn = DbConnection::establish(path.to_str().unwrap()).unwrap();
+    // An old install: the seeded admin next to a real user.
+    conn.batch_execute(
+        "INSERT INTO users (login, password_hash, role) VALUES \
+         ('admin', '$argon2id$v=19$m=4096,t=3,p=1$2JKm3pgLt+Lwdba0QiCWvA$aft9htzjsjfxK5577TAGze1hZxVbKSqpVJF3G6W0mhI', 'admin'), \
+         ('bob', 'x', 'reader')",
+    )
+    .unwrap();
+    let migration = format!(
+        "{}/migrations/2022-03-21-120000_remove_seed_users/up.sql",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    conn.batch_execute(&std::fs::read_to_string(migration).unwrap())
+        .unwrap();
+    assert_eq!(mngr.get_by_login("admin".to_string()).await, Ok(None));
+
+    assert!(matches!(
+        bootstrap::bootstrap_admin(&mngr, None, Some("secret".to_string())).await,
+        Ok(Bootstrap::Created { .. })
+    ));
+    assert!(mngr
+        .check_user("admin".to_string(), "secret".to_string())
+        .await
+        .unwrap());
+    drop(conn);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn default_credentials_test() {
+    let mngr = MockLogMngr::default();
+    mngr.with_user("admin", "changed", Role::Admin).await;
+    assert!(bootstrap::default_credentials_in_use(&mngr)
+        .await
+        .unwrap()
+        .is_empty());
+    mngr.with_user("pacan", "bandit", Role::Writer).await;
+    assert_eq!(
+        bootstrap::default_credentials_in_use(&mngr).await,
+        Ok(vec!["pacan".to_string()])
+    );
+}
+
+async fn run_command(mngr: &LoginManager, argv: &[&str]) -> Result<String, cli::CommandError> {
+    let args =
+        CliArgs::try_parse_from(std::iter::once("server").chain(argv.iter().copied())).unwrap();
+    let mut out = Vec::new();
+    cli::run(args.command.unwrap(), mngr, &mut out).await?;
+    Ok(String::from_utf8(out).unwrap())
+}
+
+#[tokio::test]
+async fn sqlite_cli_user_commands_test() {
+    let (mngr, path) = sqlite_login_manager("cli_user_commands", 1);
+    assert_eq!(
+        run_command(
+            &mngr,
+            &[
+                "user",
+                "add",
+                "root",
+                "--role",
+                "admin",
+                "--password",
+                "s3cret"
+            ]
+        )
+        .await,
+        Ok("Created admin user root\n".to_string())
+    );
+    assert_eq!(
+        run_command(&mngr, &["user", "add", "root"]).await,
+        Err(cli::CommandError::UserExists("root".to_string()))
+    );
+    let created = run_command(&mngr, &["user", "add", "bob"]).await.unwrap();
+    let password = created
+        .strip_prefix("Created reader user bob\nPassword: ")
+        .unwrap()
+        .trim_end();
+    assert!(mngr
+        .check_user("bob".to_string(), password.to_string())
+        .await
+        .unwrap());
+    assert_eq!(
+        run_command(&mngr, &["user", "list"]).await,
+        Ok("root\tadmin\nbob\treader\n".to_string())
+    );
+
+    let token = mngr
+        .create_session("root".to_string(), None)
+        .await
+        .unwrap()
+        .token;
+    assert_eq!(
+        run_command(&mngr, &["user", "passwd", "root", "--password", "changed"]).await,
+        Ok("Password of root changed\nRevoked 1 session(s) of root\n".to_string())
+    );
+    assert_eq!(mngr.check_token(token).await, Ok(false));
+    assert!(mngr
+        .check_user("root".to_string(), "changed".to_string())
+        .await
+        .unwrap());
+    assert_eq!(
+        run_command(&mngr, &["user", "passwd", "nobody"]).await,
+        Err(cli::CommandError::UnknownUser("nobody".to_string()))
+    );
+
+    let token = mngr
+        .create_session("root".to_string(), None)
+        .await
+        .unwrap()
+        .token;
+    mngr.create_session("root".to_string(), None).await.unwrap();
+    assert_eq!(
+        run_command(&mngr, &["user", "revoke-tokens", "root"]).await,
+        Ok("Revoked 2 session(s) of root\n".to_string())
+    );
+    assert_eq!(mngr.check_token(token).await, Ok(false));
+    assert_eq!(
+        run_command(&mngr, &["user", "revoke-tokens", "nobody"]).await,
+        Err(cli::CommandError::UnknownUser("nobody".to_string()))
+    );
+
+    assert_eq!(
+        run_command(&mngr, &["user", "delete", "bob"]).await,
+        Ok("Deleted user bob\n".to_string())
+    );
+    assert_eq!(mngr.get_by_login("bob".to_string()).await, Ok(None));
+    assert_eq!(
+        run_command(&mngr, &["user", "delete", "bob"]).await,
+        Err(cli::CommandError::UnknownUser("bob".to_string()))
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn sqlite_cli_history_command_test() {
+    let (mngr, path) = sqlite_login_manager("cli_history_command", 1);
+    let token = mngr
+        .create_session("root".to_string(), None)
+        .await
+        .unwrap()
+        .token;
+    assert!(mngr.check_token(token).await.unwrap());
+    mngr.record_audit(audit_entry(
+        "root",
+        AuditEvent::GetUser {
+            login: "bob".to_string(),
+        },
+    ))
+    .await
+    .unwrap();
+
+    let shown = run_command(&mngr, &["history", "show", "--login", "root"])
+        .await
+        .unwrap();
+    assert!(
+        shown.ends_with("\tget_user\tuser bob\tsuccess\t200\n"),
+        "{:?}",
+        shown
+    );
+    assert_eq!(
+        run_command(
+            &mngr,
+            &[
+                "history",
+                "show",
+                "--login",
+                "root",
+                "--since",
+                "9999-01-01"
+            ]
+        )
+        .await,
+        Ok(String::new())
+    );
+    assert!(CliArgs::try_parse_from([
+        "server",
+        "history",
+        "show",
+        "--login",
+        "root",
+        "--since",
+        "yesterday"
+    ])
+    .is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn metrics_route_test() {
+    let mngr = MockLogMngr::default();
+    mngr.with_user("123", "321", Role::Admin).await;
+    let data_path_routes = routes::login_filter_fcn(mngr, Limits::default().logins)
+        .await
+        .or(routes::metrics_fcn().await)
+        .recover(problem::handle_rejection)
+        .with(metrics::track_requests());
+
+    let req_test = warp::test::request()
+        .path("/login")
+        .header("login", "123")
+        .header("password", "bad")
+        .reply(&data_path_routes)
+        .await;
+    assert_eq!(req_test.status(), StatusCode::UNAUTHORIZED);
+
+    let req_test = warp::test::request()
+        .path("/metrics")
+        .reply(&data_path_routes)
+        .await;
+    assert_eq!(req_test.status(), StatusCode::OK);
+    assert!(req_test.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let body = String::from_utf8(req_test.body().to_vec()).unwrap();
+    assert!(body.contains(r#"http_requests_total{method="GET",route="/login",status="401"}"#));
+    assert!(body.contains(
+        r#"http_request_duration_seconds_count{method="GET",route="/login",status="401"}"#
+    ));
+    assert!(body.contains(r#"logins_total{outcome="failure"}"#));
+}
+
+#[test]
+fn route_label_test() {
+    assert_eq!(routes::route_label("/data"), "/data");
+    assert_eq!(routes::route_label("/data/42"), "/data/{id}");
+    assert_eq!(routes::route_label("/users/bob"), "/users/{login}");
+    assert_eq!(routes::route_label("/history"), "/history");
+    assert_eq!(routes::route_label("/history/bob"), "/history/{login}");
+    assert_eq!(routes::route_label("/"), "unmatched");
+    assert_eq!(routes::route_label("/users/bob/extra"), "unmatched");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn sqlite_close_test() {
+    let (mngr, path) = sqlite_login_manager("close", 2);
+    bootstrap::bootstrap_admin(&mngr, None, Some("admin".to_string()))
+        .await
+        .unwrap();
+    let mut running = {
+        let mngr = mngr.clone();
+        Box::pin(async move {
+            mngr.check_user("admin".to_string(), "admin".to_string())
+                .await
+        })
+    };
+    // Polled once, the check holds its connection slot before `close` runs.
+    assert!(futures_util::poll!(&mut running).is_pending());
+    let running = tokio::spawn(running);
+    assert!(mngr.close(std::time::Duration::from_secs(5)).await);
+    assert_eq!(running.await.unwrap(), Ok(true));
+    assert!(matches!(
+        mngr.get_users_list().await,
+        Err(LoginError::Unavailable(_))
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn rate_limiter_test() {
+    let limiter = RateLimiter::new(1.0, 2);
+    assert!(limiter.check("ip:1").is_ok());
+    assert!(limiter.check("ip:1").is_ok());
+    let retry_after = limiter.check("ip:1").unwrap_err();
+    assert!(retry_after > std::time::Duration::ZERO);
+    assert!(retry_after <= std::time::Duration::from_secs(1));
+    // Keys have their own buckets.
+    assert!(limiter.check("ip:2").is_ok());
+
+    let disabled = RateLimiter::new(0.0, 0);
+    assert!((0..100).all(|_| disabled.check("ip:1").is_ok()));
+}
+
+#[test]
+fn login_lockout_test() {
+    let secs = std::time::Duration::from_secs;
+    let lockout = LoginLockout::new(3, secs(30), secs(100));
+    assert_eq!(lockout.record_failure("bob"), None);
+    assert_eq!(lockout.record_failure("bob"), None);
+    assert!(lockout.check("bob").is_ok());
+    assert_eq!(lockout.record_failure("bob"), Some(secs(30)));
+    assert!(lockout.check("bob").unwrap_err() <= secs(30));
+    assert!(lockout.check("alice").is_ok());
+    assert_eq!(lockout.record_failure("bob"), Some(secs(60)));
+    assert_eq!(lockout.record_failure("bob"), Some(secs(100)));
+    assert_eq!(lockout.record_failure("bob"), Some(secs(100)));
+
+    lockout.record_success("bob");
+    assert!(lockout.check("bob").is_ok());
+    assert_eq!(lockout.record_failure("bob"), None);
+}
+
+#[test]
+fn login_lockout_spray_test() {
+    let secs = std::time::Duration::from_secs;
+    let lockout = LoginLockout::new(2, secs(30), secs(3600));
+    assert_eq!(lockout.record_failure("old"), None);
+    lockout.record_failure("bob");
+    assert_eq!(lockout.record_failure("bob"), Some(secs(30)));
+    for i in 0..20_000 {
+        assert_eq!(lockout.record_failure(&format!("spray{}", i)), None);
+    }
+    // Locked logins survive pruning, the oldest unlocked ones do not.
+    assert!(lockout.check("bob").is_err());
+    assert_eq!(lockout.record_failure("old"), None);
+}
+
+#[tokio::test]
+async fn login_lockout_route_test() {
+    let mngr = MockLogMngr::default();
+    mngr.with_user("123", "321", Role::Admin).await;
+    let lockout = Arc::new(LoginLockout::new(
+        2,
+        std::time::Duration::from_secs(30),
+        std::time::Duration::from_secs(60),
+    ));
+    let data_path_routes = routes::login_filter_fcn(mngr, lockout)
+        .await
+        .recover(problem::handle_rejection);
+    let login = |password: &'static str| {
+        warp::test::request()
+            .path("/login")
+            .header("login", "123")
+            .header("password", password)
+            .reply(&data_path_routes)
+    };
+
+    assert_eq!(login("bad").await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(login("bad").await.status(), StatusCode::UNAUTHORIZED);
+    // Locked now, even with the right password.
+    let req_test = login("321").await;
+    assert_eq!(req_test.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = req_test.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=30).contains(&retry_after));
+    let problem: problem::Problem = serde_json::from_slice(req_test.body()).unwrap();
+    assert_eq!(problem.code, "login_locked");
+}
+
+#[tokio::test]
+async fn rate_limit_route_test() {
+    let limiter = Arc::new(RateLimiter::new(1.0, 2));
+    let data_path_routes = ratelimit::limit(limiter)
+        .and(routes::healthz_fcn().await)
+        .recover(problem::handle_rejection);
+    let get = |ip: [u8; 4], token: Option<&'static str>| {
+        let req = warp::test::request()
+            .path("/healthz")
+            .remote_addr((ip, 40000).into());
+        let req = match token {
+            Some(token) => req.header("autorization", token),
+            None => req,
+        };
+        req.reply(&data_path_routes)
+    };
+
+    assert_eq!(get([10, 0, 0, 1], None).await.status(), StatusCode::OK);
+    assert_eq!(get([10, 0, 0, 1], None).await.status(), StatusCode::OK);
+    let req_test = get([10, 0, 0, 1], None).await;
+    assert_eq!(req_test.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(req_test.headers()["retry-after"], "1");
+
+    // A token is limited across addresses.
+    assert_eq!(
+        get([10, 0, 0, 2], Some("abc")).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        get([10, 0, 0, 3], Some("abc")).await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        get([10, 0, 0, 4], Some("abc")).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+}
+
+#[tokio::test]
+async fn sqlite_audit_history_test() {
+    let (mngr, path) = sqlite_login_manager("audit_history", 1);
+    let user = SimplifiedUser {
+        login: "bob".to_string(),
+        password: "secret".to_string(),
+        role: Some(Role::Reader),
+    };
+    mngr.record_audit(audit_entry("admin", AuditEvent::CreateUser(user.clone())))
+        .await
+        .unwrap();
+    let mngr = mngr.with_audit(AuditConfig {
+        capture_payloads: true,
+        ..AuditConfig::default()
+    });
+    mngr.record_audit(AuditEntry {
+        status: StatusCode::CONFLICT,
+        ..audit_entry("admin", AuditEvent::CreateUser(user))
+    })
+    .await
+    .unwrap();
+
+    let mut history = mngr
+        .query_history(HistoryQuery {
+            login: Some("admin".to_string()),
+            ..HistoryQuery::default()
+        })
+        .await
+        .unwrap()
+        .items;
+    history.sort_by_key(|x| x.payload.is_some());
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].action, "create_user");
+    assert_eq!(history[0].target_type, "user");
+    assert_eq!(history[0].target_id, Some("bob".to_string()));
+    assert_eq!(history[0].outcome, Outcome::Success);
+    assert_eq!(history[0].status_code, Some(200));
+    assert_eq!(history[0].latency_ms, 3);
+    assert_eq!(history[0].client_ip, Some("127.0.0.1".to_string()));
+    assert_eq!(history[0].request_id, Some("req-1".to_string()));
+    assert_eq!(history[0].payload, None);
+    assert_eq!(history[1].outcome, Outcome::Failure);
+    assert_eq!(
+        history[1].payload,
+        Some(serde_json::json!({"login": "bob", "password": "***", "role": "reader"}))
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn sqlite_history_query_test() {
+    let (mngr, path) = sqlite_login_manager("history_query", 1);
+    let conn = DbConnection::establish(path.to_str().unwrap()).unwrap();
+    for (login, event, minute) in [
+        ("admin", AuditEvent::ListUsers, 0),
+        ("admin", AuditEvent::ListSessions, 1),
+        ("bob", AuditEvent::ListUsers, 2),
+        ("admin", AuditEvent::ListUsers, 2),
+        ("admin", AuditEvent::ListUsers, 3),
+    ] {
+        History::add_element(&conn, history_at(login, event, minute)).unwrap();
+    }
+    let minutes = |page: &HistoryPage| {
+        page.items
+            .iter()
+            .map(|x| x.time.format("%M").to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let query = HistoryQuery {
+        login: Some("admin".to_string()),
+        limit: Some(2),
+        ..HistoryQuery::default()
+    };
+    let first = mngr.query_history(query.clone()).await.unwrap();
+    assert_eq!(minutes(&first), vec!["03", "02"]);
+    let second = mngr
+        .query_history(HistoryQuery {
+            cursor: first.next_cursor,
+            ..query.clone()
+        })
+        .await
+        .unwrap();
+    assert_eq!(minutes(&second), vec!["01", "00"]);
+    assert_eq!(second.next_cursor, None);
+
+    // Entries sharing a timestamp are split across pages by id.
+    let query = HistoryQuery {
+        from: Some(history_time(1)),
+        to: Some(history_time(3)),
+        action: Some("list_users".to_string()),
+        limit: Some(1),
+        order: Some(SortOrder::Asc),
+        ..HistoryQuery::default()
+    };
+    let first = mngr.query_history(query.clone()).await.unwrap();
+    let second = mngr
+        .query_history(HistoryQuery {
+            cursor: first.next_cursor.clone(),
+            ..query
+        })
+        .await
+        .unwrap();
+    assert_eq!(minutes(&first), vec!["02"]);
+    assert_eq!(minutes(&second), vec!["02"]);
+    assert!(first.items[0].id < second.items[0].id);
+    assert_eq!(second.next_cursor, None);
+
+    assert!(matches!(
+        mngr.query_history(HistoryQuery {
+            cursor: Some("nonsense".to_string()),
+            ..HistoryQuery::default()
+        })
+        .await,
+        Err(LoginError::Invalid(_))
+    ));
+    drop(conn);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn sqlite_history_retention_test() {
+    use std::io::BufRead;
+    let (mngr, path) = sqlite_login_manager("history_retention", 1);
+    let conn = DbConnection::establish(path.to_str().unwrap()).unwrap();
+    let now = chrono::Utc::now().naive_utc();
+    for (login, days) in [("admin", 10), ("admin", 5), ("admin", 1), ("admin", 0)]
+        .into_iter()
+        .chain([("bob", 10), ("bob", 0)])
+    {
+        History::add_element(
+            &conn,
+            History {
+                tms: now - chrono::Duration::days(days),
+                ..audit_entry(login, AuditEvent::ListSessions).to_history(&AuditConfig::default())
+            },
+        )
+        .unwrap();
+    }
+    let archive_dir = std::env::temp_dir().join(format!("history_archive_{}", std::process::id()));
+    let config = RetentionConfig {
+        max_age_days: Some(7),
+        max_rows_per_user: Some(2),
+        batch_size: 1,
+        archive_dir: Some(archive_dir.clone()),
+        ..RetentionConfig::default()
+    };
+
+    let report = retention::purge(&mngr, &config).await.unwrap();
+    assert_eq!(report.expired, 2);
+    assert_eq!(report.trimmed, 1);
+    let remaining = History::query(&conn, &HistoryQuery::default(), None).unwrap();
+    let mut remaining: Vec<_> = remaining.iter().map(|x| x.login.as_str()).collect();
+    remaining.sort();
+    assert_eq!(remaining, vec!["admin", "admin", "bob"]);
+
+    // One gzip member per batch, read back as a single stream.
+    let file = std::fs::File::open(report.archive.unwrap()).unwrap();
+    let archived: Vec<AuditRecord> =
+        std::io::BufReader::new(flate2::read::MultiGzDecoder::new(file))
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+    assert_eq!(archived.len(), 3);
+    assert!(archived.iter().all(|x| x.action == "list_sessions"));
+
+    let report = retention::purge(&mngr, &config).await.unwrap();
+    assert_eq!(report, retention::PurgeReport::default());
+    drop(conn);
+    std::fs::remove_dir_all(archive_dir).unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn sqlite_history_migration_test() {
+    use diesel::connection::SimpleConnection;
+    let path = std::env::temp_dir().join(format!("history_migration_{}.db", std::process::id()));
+    std::fs::remove_file(&path).unwrap_or(());
+    let conn = DbConnection::establish(path.to_str().unwrap()).unwrap();
+    migrations::run(&conn).unwrap();
+    let migration = |name: &str, direction: &str| {
+        let path = format!(
+            "{}/migrations/{}/{}.sql",
+            env!("CARGO_MANIFEST_DIR"),
+            name,
+            direction
+        );
+        conn.batch_execute(&std::fs::read_to_string(path).unwrap())
+            .unwrap();
+    };
+    let history_migrations = [
+        "2022-03-28-090000_structured_history",
+        "2022-04-04-090000_history_token_fingerprint",
+        "2022-04-11-090000_history_query_indexes",
+    ];
+    for name in history_migrations.iter().rev() {
+        migration(name, "down");
+    }
+    conn.batch_execute(
+        "INSERT INTO history (id, login, request, tms) \
+         VALUES ('1', 'admin', 'Insert user SimplifiedUser { login: \"bob\", \
+         password: \"hunter2\", role: None }', '2022-03-01 10:00:00')",
+    )
+    .unwrap();
+    for name in history_migrations {
+        migration(name, "up");
+    }
+
+    let record = AuditRecord::from(
+        History::query(&conn, &HistoryQuery::default(), None)
+            .unwrap()
+            .remove(0),
+    );
+    assert_eq!(record.action, "legacy");
+    assert_eq!(record.outcome, Outcome::Unknown);
+    assert_eq!(record.status_code, None);
+    assert_eq!(record.payload, None);
+    assert!(!serde_json::to_string(&record).unwrap().contains("hunter2"));
+    drop(conn);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn audit_outcome_route_test() {
+    let mngr = MockLogMngr::default();
+    let admin = mngr.with_user("admin", "admin", Role::Admin).await;
+    let reader = mngr.with_user("reader", "reader", Role::Reader).await;
+    let data_path_routes = routes::get_certain_user(mngr.clone())
+        .await
+        .or(routes::login_filter_fcn(mngr.clone(), Limits::default().logins).await)
+        .recover(problem::handle_rejection);
+    let get_user = |token: &str, login: &str| {
+        warp::test::request()
+            .path(&format!("/users/{}", login))
+            .header("autorization", token)
+            .header("x-request-id", "abc-1")
+            .remote_addr(([10, 0, 0, 7], 40000).into())
+            .reply(&data_path_routes)
+    };
+
+    assert_eq!(
+        get_user("not a token", "admin").await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        get_user(&reader, "admin").await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        get_user(&admin, "nobody").await.status(),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(get_user(&admin, "reader").await.status(), StatusCode::OK);
+    let req_test = warp::test::request()
+        .path("/login")
+        .header("login", "reader")
+        .header("password", "wrong")
+        .reply(&data_path_routes)
+        .await;
+    assert_eq!(req_test.status(), StatusCode::UNAUTHORIZED);
+
+    let entries = mngr.audit.read().unwrap().clone();
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|x| {
+            (
+                x.login.as_str(),
+                x.event.action(),
+                x.event.target_id(),
+                x.status.as_u16(),
+                Outcome::from_status(x.status),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (
+                audit::UNKNOWN_LOGIN,
+                "get_user",
+                Some("admin".to_string()),
+                403,
+                Outcome::Denied
+            ),
+            (
+                "reader",
+                "get_user",
+                Some("admin".to_string()),
+                403,
+                Outcome::Denied
+            ),
+            (
+                "admin",
+                "get_user",
+                Some("nobody".to_string()),
+                404,
+                Outcome::Failure
+            ),
+            (
+                "admin",
+                "get_user",
+                Some("reader".to_string()),
+                200,
+                Outcome::Success
+            ),
+            (
+                "reader",
+                "login",
+                Some("reader".to_string()),
+                401,
+                Outcome::Denied
+            ),
+        ]
+    );
+    assert_eq!(
+        entries[0].token_fingerprint,
+        Some(audit::token_fingerprint("not a token"))
+    );
+    assert_eq!(entries[0].token_fingerprint.as_ref().unwrap().len(), 16);
+    assert_eq!(entries[0].request_id, "abc-1");
+    assert_eq!(entries[0].client_ip, Some([10, 0, 0, 7].into()));
+    assert_eq!(entries[4].token_fingerprint, None);
+}
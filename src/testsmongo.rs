@@ -1,14 +1,15 @@
 #[cfg(all(test, feature = "integration_tests"))]
 mod tests {
-    use std::collections::BTreeMap;
     use std::sync::Arc;
-    use std::sync::RwLock;
 
+    use crate::dataerror::DataError;
     use crate::loginmanager::LogMngTrait;
     use crate::loginmanager::SimplifiedUser;
     use crate::mongodbprovider::*;
     use crate::mydatastruct;
     use crate::mydatastruct::*;
+    use crate::permissions::Role;
+    use crate::problem;
     use crate::routes::get_filter_fcn;
     use crate::routes::insert_filter_fcn;
     use async_trait::async_trait;
@@ -35,6 +36,8 @@ mod tests {
                 port,
                 user_name: db_name,
                 password: db_pass,
+                database: DEFAULT_DATABASE.to_string(),
+                collection: DEFAULT_COLLECTION.to_string(),
             })
             .await;
             FakeMongoDbProvider {
@@ -46,12 +49,31 @@ mod tests {
 
     #[async_trait]
     impl MongoDBProviderTrait for FakeMongoDbProvider<'_> {
-        async fn insert_struct_to_db(&self, data: MyData) -> Result<(), String> {
+        async fn insert_struct_to_db(&self, data: MyData) -> Result<(), DataError> {
             self.provider.insert_struct_to_db(data).await
         }
-        async fn read_from(&self, id: String) -> Result<Vec<MyData>, String> {
+        async fn read_from(&self, id: String) -> Result<Vec<MyData>, DataError> {
             self.provider.read_from(id).await
         }
+        async fn replace_struct(&self, id: String, data: MyData) -> Result<(), DataError> {
+            self.provider.replace_struct(id, data).await
+        }
+        async fn replace_if_unchanged(
+            &self,
+            current: MyData,
+            data: MyData,
+        ) -> Result<(), DataError> {
+            self.provider.replace_if_unchanged(current, data).await
+        }
+        async fn delete_struct(&self, id: String) -> Result<(), DataError> {
+            self.provider.delete_struct(id).await
+        }
+        async fn list_structs(&self, query: DataQuery) -> Result<DataPage, DataError> {
+            self.provider.list_structs(query).await
+        }
+        async fn ping(&self) -> Result<(), DataError> {
+            self.provider.ping().await
+        }
     }
 
     use testcontainers::clients;
@@ -74,7 +96,7 @@ mod tests {
         let insert_res = fake_mongo.insert_struct_to_db(test_stuct.clone()).await;
         assert!(insert_res.is_ok());
         let second_insert = fake_mongo.insert_struct_to_db(test_stuct.clone()).await;
-        assert!(second_insert.is_err());
+        assert_eq!(second_insert, Err(DataError::Duplicate("test".to_string())));
         let read_res_vec = fake_mongo.read_from("test".to_string()).await;
         assert!(read_res_vec.is_ok());
         let vec_unw = read_res_vec.unwrap();
@@ -82,6 +104,115 @@ mod tests {
         assert_eq!(vec_unw[0], test_stuct);
     }
 
+    #[tokio::test]
+    async fn mongo_replace_patch_delete_test() {
+        let docker = clients::Cli::default();
+        let fake_mongo = FakeMongoDbProvider::new(&docker, 27023).await;
+        let test_stuct = mydatastruct::create_my_struct(
+            "test".to_string(),
+            "AAA".to_string(),
+            53,
+            mydatastruct::Sex::Female,
+        );
+        let replaced = mydatastruct::create_my_struct(
+            "test".to_string(),
+            "BBB".to_string(),
+            54,
+            mydatastruct::Sex::Female,
+        );
+        let missing = fake_mongo
+            .replace_struct("test".to_string(), replaced.clone())
+            .await;
+        assert_eq!(missing, Err(DataError::NotFound));
+        assert!(fake_mongo.insert_struct_to_db(test_stuct).await.is_ok());
+        assert!(fake_mongo
+            .replace_struct("test".to_string(), replaced.clone())
+            .await
+            .is_ok());
+        let patched = fake_mongo
+            .patch_struct("test".to_string(), serde_json::json!({"age": 55}))
+            .await
+            .unwrap();
+        assert_eq!(
+            fake_mongo.read_from("test".to_string()).await.unwrap(),
+            vec![patched.clone()]
+        );
+        // `replaced` is stale once the patch went through.
+        assert_eq!(
+            fake_mongo
+                .replace_if_unchanged(replaced.clone(), replaced.clone())
+                .await,
+            Err(DataError::Conflict("test".to_string()))
+        );
+        assert!(fake_mongo.delete_struct("test".to_string()).await.is_ok());
+        assert_eq!(
+            fake_mongo.read_from("test".to_string()).await,
+            Err(DataError::NotFound)
+        );
+        assert_eq!(
+            fake_mongo.replace_if_unchanged(patched, replaced).await,
+            Err(DataError::NotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn mongo_list_test() {
+        let docker = clients::Cli::default();
+        let fake_mongo = FakeMongoDbProvider::new(&docker, 27024).await;
+        let items: Vec<MyData> = (0..5)
+            .map(|i| {
+                mydatastruct::create_my_struct(
+                    format!("id{}", i),
+                    "AAA".to_string(),
+                    50 - i,
+                    mydatastruct::Sex::Male,
+                )
+            })
+            .collect();
+        for item in &items {
+            assert!(fake_mongo.insert_struct_to_db(item.clone()).await.is_ok());
+        }
+        let other = mydatastruct::create_my_struct(
+            "other".to_string(),
+            "BBB".to_string(),
+            48,
+            mydatastruct::Sex::Female,
+        );
+        assert!(fake_mongo.insert_struct_to_db(other.clone()).await.is_ok());
+
+        let query = DataQuery {
+            first_name: Some("AAA".to_string()),
+            min_age: Some(47),
+            sort: Some(SortField::Age),
+            limit: Some(2),
+            ..DataQuery::default()
+        };
+        let page = fake_mongo.list_structs(query.clone()).await.unwrap();
+        assert_eq!(page.items, vec![items[3].clone(), items[2].clone()]);
+        let query = DataQuery {
+            cursor: page.next_cursor,
+            ..query
+        };
+        let page = fake_mongo.list_structs(query).await.unwrap();
+        assert_eq!(page.items, vec![items[1].clone(), items[0].clone()]);
+        assert_eq!(page.next_cursor, None);
+
+        let query = DataQuery {
+            sex: Some(mydatastruct::Sex::Female),
+            ..DataQuery::default()
+        };
+        let page = fake_mongo.list_structs(query).await.unwrap();
+        assert_eq!(page.items, vec![other]);
+        let query = DataQuery {
+            cursor: Some("bad".to_string()),
+            ..DataQuery::default()
+        };
+        assert!(matches!(
+            fake_mongo.list_structs(query).await,
+            Err(DataError::Validation(_))
+        ));
+    }
+
     //TODO: test REST routes with FakeMongo
 
     use crate::testlogin::MockLogMngr;
@@ -97,17 +228,23 @@ mod tests {
     async fn insert_route_test() {
         let docker = clients::Cli::default();
         let db_provider = FakeMongoDbProvider::new(&docker, 27021).await;
-        let mngr = MockLogMngr {
-            inner: Arc::new(RwLock::new(BTreeMap::new())),
-        };
+        let mngr = MockLogMngr::default();
         let test_stuct = SimplifiedUser {
             login: "123".to_string(),
             password: "321".to_string(),
+            role: Some(Role::Writer),
         };
-        mngr.insert_new_user(test_stuct.clone());
+        mngr.insert_new_user(test_stuct.clone()).await.unwrap();
+        let token = mngr
+            .create_session(test_stuct.login.clone(), None)
+            .await
+            .unwrap()
+            .token;
         let insert_route = insert_filter_fcn(db_provider.provider.clone(), mngr.clone()).await;
         let data_path = warp::path("data");
-        let data_path_routes = data_path.and(insert_route);
+        let data_path_routes = data_path
+            .and(insert_route)
+            .recover(problem::handle_rejection);
 
         let test_stuct = mydatastruct::create_my_struct(
             "test".to_string(),
@@ -128,7 +265,7 @@ mod tests {
         let req_test = warp::test::request()
             .path("/data")
             .method("POST")
-            .header("autorization", "123")
+            .header("autorization", &token)
             .body(serde_json::to_string(&test_body_request).unwrap())
             .reply(&data_path_routes.clone())
             .await;
@@ -137,28 +274,32 @@ mod tests {
         let req_test = warp::test::request()
             .path("/data")
             .method("POST")
-            .header("autorization", "123")
+            .header("autorization", &token)
             .body(serde_json::to_string(&test_stuct).unwrap())
             .reply(&data_path_routes.clone())
             .await;
-        assert_eq!(req_test.status(), StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(req_test.status(), StatusCode::CONFLICT);
     }
 
     #[tokio::test]
     async fn get_route_test() {
         let docker = clients::Cli::default();
         let db_provider = FakeMongoDbProvider::new(&docker, 27022).await;
-        let mngr = MockLogMngr {
-            inner: Arc::new(RwLock::new(BTreeMap::new())),
-        };
+        let mngr = MockLogMngr::default();
         let test_stuct = SimplifiedUser {
             login: "123".to_string(),
             password: "321".to_string(),
+            role: Some(Role::Writer),
         };
-        mngr.insert_new_user(test_stuct.clone());
+        mngr.insert_new_user(test_stuct.clone()).await.unwrap();
+        let token = mngr
+            .create_session(test_stuct.login.clone(), None)
+            .await
+            .unwrap()
+            .token;
         let get_route = get_filter_fcn(db_provider.provider.clone(), mngr.clone()).await;
         let data_path = warp::path("data");
-        let data_path_routes = data_path.and(get_route);
+        let data_path_routes = data_path.and(get_route).recover(problem::handle_rejection);
 
         let test_stuct = mydatastruct::create_my_struct(
             "test".to_string(),
@@ -170,7 +311,7 @@ mod tests {
         let req_test = warp::test::request()
             .path("/data/test")
             .method("GET")
-            .header("autorization", "123")
+            .header("autorization", &token)
             .reply(&data_path_routes.clone())
             .await;
         assert_eq!(req_test.status(), StatusCode::NOT_FOUND);
@@ -184,7 +325,7 @@ mod tests {
         let req_test = warp::test::request()
             .path("/data/test")
             .method("GET")
-            .header("autorization", "123")
+            .header("autorization", &token)
             .reply(&data_path_routes.clone())
             .await;
 
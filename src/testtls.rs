@@ -0,0 +1,193 @@
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::oneshot,
+};
+use tokio_rustls::TlsConnector;
+use warp::Filter;
+
+use crate::{
+    config::TlsConfig,
+    permissions::Role,
+    problem, routes,
+    testlogin::MockLogMngr,
+    tls::{self, CertResolver},
+};
+
+fn testdata(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata/tls")
+        .join(name)
+}
+
+fn pem_certs(name: &str) -> Vec<Certificate> {
+    let file = fs::File::open(testdata(name)).unwrap();
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .unwrap()
+        .into_iter()
+        .map(Certificate)
+        .collect()
+}
+
+fn pem_key(name: &str) -> PrivateKey {
+    let file = fs::File::open(testdata(name)).unwrap();
+    PrivateKey(
+        rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(file))
+            .unwrap()
+            .remove(0),
+    )
+}
+
+fn tls_config(client_ca: bool) -> TlsConfig {
+    TlsConfig {
+        cert_path: Some(testdata("server.pem")),
+        key_path: Some(testdata("server.key")),
+        client_ca_path: client_ca.then(|| testdata("ca.pem")),
+    }
+}
+
+/// Sends a bare HTTP/1.1 request over TLS and returns the raw response.
+async fn https_get(port: u16, path: &str, client_cert: bool) -> String {
+    let mut roots = RootCertStore::empty();
+    roots.add(&pem_certs("ca.pem")[0]).unwrap();
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = if client_cert {
+        builder
+            .with_client_auth_cert(pem_certs("client.pem"), pem_key("client.key"))
+            .unwrap()
+    } else {
+        builder.with_no_client_auth()
+    };
+    let tcp = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), tcp)
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+                path
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap_or(0);
+    response
+}
+
+#[test]
+fn tls_client_login_test() {
+    assert_eq!(
+        tls::client_login(&pem_certs("client.pem")[0]),
+        Some("admin".to_string())
+    );
+    assert_eq!(
+        tls::client_login(&pem_certs("server.pem")[0]),
+        Some("localhost".to_string())
+    );
+    assert_eq!(tls::client_login(&Certificate(vec![1, 2, 3])), None);
+}
+
+#[test]
+fn tls_config_errors_test() {
+    let missing = TlsConfig {
+        cert_path: Some(testdata("missing.pem")),
+        ..tls_config(false)
+    };
+    assert!(matches!(
+        tls::server_config(&missing),
+        Err(tls::TlsError::Read(..))
+    ));
+    let swapped = TlsConfig {
+        cert_path: Some(testdata("server.key")),
+        key_path: Some(testdata("server.pem")),
+        client_ca_path: None,
+    };
+    assert!(matches!(
+        tls::server_config(&swapped),
+        Err(tls::TlsError::Invalid(..))
+    ));
+}
+
+#[test]
+fn tls_reload_test() {
+    let dir = std::env::temp_dir().join(format!("tls_reload_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("server.pem"), dir.join("server.key"));
+    let install = |name: &str, modified: SystemTime| {
+        for (from, to) in [
+            (format!("{}.pem", name), &cert),
+            (format!("{}.key", name), &key),
+        ] {
+            fs::copy(testdata(&from), to).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(to)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+    };
+    let start = SystemTime::now() - Duration::from_secs(60);
+    install("server", start);
+
+    let resolver = CertResolver::new(cert.clone(), key.clone()).unwrap();
+    assert_eq!(resolver.current().cert, pem_certs("server.pem"));
+    assert!(!resolver.reload_if_changed().unwrap());
+
+    install("server2", start + Duration::from_secs(10));
+    assert!(resolver.reload_if_changed().unwrap());
+    assert_eq!(resolver.current().cert, pem_certs("server2.pem"));
+
+    // A half-written replacement keeps the certificate in use.
+    fs::write(&key, "not a key").unwrap();
+    assert!(resolver.reload_if_changed().is_err());
+    assert_eq!(resolver.current().cert, pem_certs("server2.pem"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn tls_client_certificate_test() {
+    let mngr = MockLogMngr::default();
+    mngr.with_user("admin", "secret", Role::Admin).await;
+    let routes = routes::get_users_fcn(mngr)
+        .await
+        .recover(problem::handle_rejection);
+    let (config, _) = tls::server_config(&tls_config(true)).unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let (address, server) =
+        tls::bind_with_graceful_shutdown(([127, 0, 0, 1], 0).into(), config, routes, async {
+            stopped.await.ok();
+        })
+        .await
+        .unwrap();
+    let server = tokio::spawn(server);
+
+    let response = https_get(address.port(), "/users", true).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains(r#""login":"admin""#));
+
+    let response = https_get(address.port(), "/users", false).await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert!(response.contains("missing_header"));
+
+    stop.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap();
+}
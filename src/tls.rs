@@ -0,0 +1,326 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    fs::{self, File},
+    future::Future,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use hyper::{
+    server::conn::Http,
+    service::{service_fn, Service},
+    Body, Request,
+};
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio::{net::TcpListener, sync::watch, task::JoinSet, time};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+use warp::{Filter, Reply};
+
+use crate::config::TlsConfig;
+
+/// How often the certificate files are checked for changes.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Login taken from the common name of a verified client certificate.
+///
+/// Only the TLS server puts it into request extensions, so plain HTTP
+/// clients can not forge it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientIdentity {
+    pub login: String,
+}
+
+/// Remote address of a TLS connection, which `warp::addr::remote`
+/// does not see behind the custom acceptor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerAddr(pub SocketAddr);
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, io::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Read(path, err) => write!(f, "can not read {}: {}", path.display(), err),
+            TlsError::Invalid(path, err) => write!(f, "invalid {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// Serves the current certificate and swaps it when the files change.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl CertResolver {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, TlsError> {
+        let modified = last_modified(&[&cert_path, &key_path]);
+        let current = load_certified_key(&cert_path, &key_path)?;
+        Ok(CertResolver {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(current)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reloads the certificate when either file changed since the last load.
+    /// A broken replacement is reported and the previous certificate kept.
+    pub fn reload_if_changed(&self) -> Result<bool, TlsError> {
+        let modified = last_modified(&[&self.cert_path, &self.key_path]);
+        let mut last = self.modified.lock().unwrap();
+        if modified == *last {
+            return Ok(false);
+        }
+        let current = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(current);
+        *last = modified;
+        info!("Reloaded TLS certificate {}", self.cert_path.display());
+        Ok(true)
+    }
+
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Polls the certificate files every `interval` until the resolver is
+    /// no longer used.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let resolver = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticks = time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let resolver = match resolver.upgrade() {
+                    Some(resolver) => resolver,
+                    None => return,
+                };
+                if let Err(err) = resolver.reload_if_changed() {
+                    warn!("Keeping the previous TLS certificate: {}", err);
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// Newest modification time of `paths`, `None` when one can not be read.
+fn last_modified(paths: &[&Path]) -> Option<SystemTime> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|x| x.modified()).ok())
+        .try_fold(SystemTime::UNIX_EPOCH, |newest, x| Some(newest.max(x?)))
+}
+
+fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>, TlsError> {
+    let file = File::open(path).map_err(|err| TlsError::Read(path.to_path_buf(), err))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| TlsError::Read(path.to_path_buf(), err))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::Invalid(
+            path.to_path_buf(),
+            "no certificate found".to_string(),
+        ));
+    }
+    Ok(certs)
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = load_certs(cert_path)?;
+    let key = read_pem(key_path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            TlsError::Invalid(key_path.to_path_buf(), "no private key found".to_string())
+        })?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|err| TlsError::Invalid(key_path.to_path_buf(), err.to_string()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Builds the rustls configuration described by `tls`.
+///
+/// With a client CA, certificates are requested but optional: clients
+/// without one still authenticate with a token.
+pub fn server_config(tls: &TlsConfig) -> Result<(Arc<ServerConfig>, Arc<CertResolver>), TlsError> {
+    let (cert_path, key_path) = match (&tls.cert_path, &tls.key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path.clone(), key_path.clone()),
+        _ => {
+            return Err(TlsError::Invalid(
+                PathBuf::new(),
+                "both a certificate and a key are required".to_string(),
+            ))
+        }
+    };
+    let resolver = Arc::new(CertResolver::new(cert_path, key_path)?);
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &tls.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|err| TlsError::Invalid(ca_path.clone(), err.to_string()))?;
+            }
+            builder.with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok((Arc::new(config), resolver))
+}
+
+/// Common name of a client certificate, used as the caller's login.
+pub fn client_login(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let login = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(login.to_string())
+}
+
+/// TLS counterpart of `warp::Server::bind_with_graceful_shutdown`.
+///
+/// After `signal` no new connections are accepted; the returned future
+/// resolves once every open connection has finished its requests.
+pub async fn bind_with_graceful_shutdown<F>(
+    addr: SocketAddr,
+    config: Arc<ServerConfig>,
+    filter: F,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<(SocketAddr, impl Future<Output = ()>)>
+where
+    F: Filter<Error = Infallible> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let address = listener.local_addr()?;
+    let acceptor = TlsAcceptor::from(config);
+    let service = warp::service(filter);
+    let server = async move {
+        let (stop, stopped) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        tokio::pin!(signal);
+        loop {
+            tokio::select! {
+                _ = &mut signal => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        let acceptor = acceptor.clone();
+                        let service = service.clone();
+                        let stopped = stopped.clone();
+                        connections.spawn(async move {
+                            serve_connection(acceptor, stream, peer, service, stopped).await
+                        });
+                    }
+                    Err(err) => warn!("Can not accept connection: {}", err),
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+        drop(listener);
+        stop.send(true).ok();
+        while connections.join_next().await.is_some() {}
+    };
+    Ok((address, server))
+}
+
+async fn serve_connection<S>(
+    acceptor: TlsAcceptor,
+    stream: tokio::net::TcpStream,
+    peer: SocketAddr,
+    service: S,
+    mut stopped: watch::Receiver<bool>,
+) where
+    S: Service<Request<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Response: Into<hyper::Response<Body>>,
+    S::Future: Send + 'static,
+{
+    let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            debug!("TLS handshake with {} failed: {}", peer, err);
+            return;
+        }
+        Err(_) => {
+            debug!("TLS handshake with {} timed out", peer);
+            return;
+        }
+    };
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(client_login)
+        .map(|login| ClientIdentity { login });
+    let handler = service_fn(move |mut req: Request<Body>| {
+        req.extensions_mut().insert(PeerAddr(peer));
+        if let Some(identity) = &identity {
+            req.extensions_mut().insert(identity.clone());
+        }
+        let mut service = service.clone();
+        async move { service.call(req).await.map(Into::into) }
+    });
+    let connection = Http::new().serve_connection(stream, handler);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = stopped.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        debug!("Connection with {} failed: {}", peer, err);
+    }
+}